@@ -0,0 +1,35 @@
+//! Optional protocol features negotiated in the handshake.
+//!
+//! Both sides send a bitmask of the capabilities they support. A feature is
+//! only used on a connection if it ends up in the mask of the
+//! HandshakeAccept message, clients must not assume anything they asked for
+//! was granted.
+
+/// Frame states are sent as DeltaFrameState, with only what changed since
+/// the previous one. See delta.rs.
+pub const COMPRESSION: u32 = 1 << 0;
+
+/// Fighter states may carry fields beyond the ones of protocol 1.1
+pub const EXTENDED_FIGHTER_FIELDS: u32 = 1 << 1;
+
+/// The client can display matches with more or less than 2 players. Clients
+/// without this capability are not sent anything about those matches.
+pub const MULTI_PLAYER: u32 = 1 << 2;
+
+/// The client answers the server's Ping messages. The server pings it when
+/// nothing was received from it for a while, and disconnects it if nothing is
+/// received from it for too long.
+pub const HEARTBEAT: u32 = 1 << 3;
+
+/// Damage, shield size and hitstun are sent as f32 instead of being
+/// quantized to u16. Only granted along with EXTENDED_FIGHTER_FIELDS.
+pub const FULL_PRECISION: u32 = 1 << 4;
+
+/// The client wants HitboxState messages with the active hitboxes of the
+/// fighters it subscribed to
+pub const HITBOXES: u32 = 1 << 5;
+
+/// Returns true if all bits of `capability` are set in `mask`
+pub fn has(mask: u32, capability: u32) -> bool {
+    mask & capability == capability
+}
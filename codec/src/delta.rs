@@ -0,0 +1,316 @@
+//! Delta compressed frame states, used instead of FrameState and
+//! ExtendedFrameState for clients with capabilities::COMPRESSION.
+//!
+//! Each fighter only carries the field groups (see fields.rs) whose values
+//! changed since the previous DeltaFrameState sent to the client. Every
+//! KEYFRAME_INTERVAL messages, and whenever the server starts over (e.g. on
+//! subscribing or at the start of a match), a keyframe with all groups is
+//! sent instead.
+//!
+//! The overflow flag of a fighter covers every group the client gets, not
+//! only the ones in the message, so that it stays set while a clamped value
+//! is carried over from an earlier message.
+//!
+//! ```text
+//! sequence        u32, increases by one per message
+//! frame           u32
+//! flags           u8, bit 0 set on keyframes
+//! fighter count   u8
+//! fighters        ExtendedFighterState layout without the frame
+//! ```
+//!
+//! Frame states may be dropped when a client can't keep up, or lost when they
+//! are sent over UDP. A client that misses a sequence number has to wait for
+//! the next keyframe, DeltaDecoder takes care of that.
+
+use crate::reader::Reader;
+use crate::{fields, DecodeError, FighterState, FrameState};
+
+pub const KEYFRAME_INTERVAL: u32 = 60;
+
+const FLAG_KEYFRAME: u8 = 1 << 0;
+
+// Every group on its own, to find out which of them changed
+const GROUPS: [u8; 8] = [
+    fields::POSITION,
+    fields::STATUS,
+    fields::DAMAGE,
+    fields::SHIELD,
+    fields::HIT_STATUS,
+    fields::INPUTS,
+    fields::ANIMATION,
+    fields::VELOCITY,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaFrameState {
+    pub sequence: u32,
+    pub keyframe: bool,
+    pub frame: u32,
+    /// See ServerMessage::ExtendedFighterState
+    pub full_precision: bool,
+    /// Field groups each fighter carries, and the fighter. Groups that
+    /// weren't sent are 0/false.
+    pub fighters: Vec<(u8, FighterState)>,
+}
+
+impl DeltaFrameState {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.frame.to_be_bytes());
+        buf.push(if self.keyframe { FLAG_KEYFRAME } else { 0 });
+        buf.push(self.fighters.len() as u8);
+        for (fields, fighter) in self.fighters.iter() {
+            fighter.encode_extended_body(*fields, self.full_precision, buf);
+        }
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let sequence = r.u32()?;
+        let frame = r.u32()?;
+        let flags = r.u8()?;
+        let count = r.u8()? as usize;
+        let mut full_precision = false;
+        let mut fighters = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (fields, precision, fighter) = FighterState::decode_extended_body(frame, r)?;
+            full_precision = precision;
+            fighters.push((fields, fighter));
+        }
+        Ok(Self {
+            sequence: sequence,
+            keyframe: flags & FLAG_KEYFRAME != 0,
+            frame: frame,
+            full_precision: full_precision,
+            fighters: fighters,
+        })
+    }
+}
+
+fn encoded_fields(state: &FighterState, group: u8, full_precision: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    state.encode_fields(group, full_precision, &mut buf);
+    buf
+}
+
+/// Remembers what was sent to one client
+pub struct DeltaEncoder {
+    sequence: u32,
+    since_keyframe: u32,
+    previous: Vec<FighterState>,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        Self {
+            sequence: 0,
+            since_keyframe: 0,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Makes the next message a keyframe
+    pub fn reset(&mut self) {
+        self.previous.clear();
+    }
+
+    /// Only the field groups in `fields` are ever sent
+    pub fn encode(&mut self, frame: &FrameState, fields: u8, full_precision: bool) -> DeltaFrameState {
+        let keyframe = self.previous.is_empty() || self.since_keyframe + 1 >= KEYFRAME_INTERVAL;
+        self.since_keyframe = if keyframe { 0 } else { self.since_keyframe + 1 };
+
+        let fighters = frame.fighters.iter().map(|fighter| {
+            let previous = self.previous.iter().find(|p| p.entry_id == fighter.entry_id);
+            let changed = match previous {
+                Some(previous) if !keyframe => GROUPS.iter()
+                    .filter(|group| *group & fields != 0)
+                    .filter(|group| {
+                        encoded_fields(previous, **group, full_precision) != encoded_fields(fighter, **group, full_precision)
+                    })
+                    .fold(0, |changed, group| changed | group),
+                _ => fields,
+            };
+            // Leave out what isn't sent, so that the message is the same
+            // after decoding it
+            let mut sent = FighterState {
+                entry_id: fighter.entry_id,
+                ..Default::default()
+            };
+            sent.copy_fields(fighter, changed);
+            sent.overflow = !full_precision && fighter.overflows(fields);
+            (changed, sent)
+        }).collect();
+
+        let delta = DeltaFrameState {
+            sequence: self.sequence,
+            keyframe: keyframe,
+            frame: frame.frame,
+            full_precision: full_precision,
+            fighters: fighters,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.previous = frame.fighters.clone();
+        delta
+    }
+}
+
+impl Default for DeltaEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rebuilds complete frame states on the client side
+pub struct DeltaDecoder {
+    // None until the first keyframe, and again after a message was missed
+    sequence: Option<u32>,
+    fighters: Vec<FighterState>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self {
+            sequence: None,
+            fighters: Vec::new(),
+        }
+    }
+
+    /// Returns None while waiting for a keyframe
+    pub fn apply(&mut self, delta: &DeltaFrameState) -> Option<FrameState> {
+        let in_sequence = self.sequence.map(|s| s.wrapping_add(1)) == Some(delta.sequence);
+        if !delta.keyframe && !in_sequence {
+            self.sequence = None;
+            return None;
+        }
+
+        let previous = if delta.keyframe { Vec::new() } else { std::mem::take(&mut self.fighters) };
+        self.fighters = delta.fighters.iter().map(|(fields, fighter)| {
+            match previous.iter().find(|p| p.entry_id == fighter.entry_id) {
+                Some(previous) => {
+                    let mut state = previous.clone();
+                    state.copy_fields(fighter, *fields);
+                    state
+                },
+                None => fighter.clone(),
+            }
+        }).collect();
+        self.sequence = Some(delta.sequence);
+
+        Some(FrameState {
+            frame: delta.frame,
+            fighters: self.fighters.clone(),
+        })
+    }
+}
+
+impl Default for DeltaDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServerMessage;
+
+    fn fighter(entry_id: u8, damage: f32, shield_size: f32) -> FighterState {
+        FighterState {
+            frame: 100,
+            entry_id: entry_id,
+            pos_x: 10.0,
+            pos_y: -2.5,
+            damage: damage,
+            shield_size: shield_size,
+            status_kind: 27,
+            motion_kind: 0x0a_1b2c_3d4e,
+            stock_count: 3,
+            facing_right: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_changed_groups_are_sent() {
+        let mut encoder = DeltaEncoder::new();
+        let first = FrameState { frame: 100, fighters: vec![fighter(0, 12.0, 50.0), fighter(1, 0.0, 50.0)] };
+        let delta = encoder.encode(&first, fields::ALL, false);
+        assert!(delta.keyframe);
+        assert!(delta.fighters.iter().all(|(fields, _)| *fields == fields::ALL));
+
+        let second = FrameState { frame: 100, fighters: vec![fighter(0, 12.0, 49.5), fighter(1, 0.0, 50.0)] };
+        let delta = encoder.encode(&second, fields::ALL, false);
+        assert!(!delta.keyframe);
+        assert_eq!(delta.fighters[0].0, fields::SHIELD);
+        assert_eq!(delta.fighters[1].0, 0);
+
+        let mut decoder = DeltaDecoder::new();
+        let msg = ServerMessage::DeltaFrameState(delta);
+        let bytes = msg.to_framed_bytes();
+        let (decoded, _) = ServerMessage::decode_framed(&bytes).unwrap();
+        assert_eq!(decoded, Ok(msg));
+
+        let ServerMessage::DeltaFrameState(delta) = decoded.unwrap() else { unreachable!() };
+        // Missed the keyframe
+        assert_eq!(decoder.apply(&delta), None);
+
+        let mut encoder = DeltaEncoder::new();
+        decoder.apply(&encoder.encode(&first, fields::ALL, false)).unwrap();
+        assert_eq!(decoder.apply(&encoder.encode(&second, fields::ALL, false)), Some(second));
+    }
+
+    #[test]
+    fn overflow_is_kept_for_carried_over_values() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let first = FrameState { frame: 100, fighters: vec![fighter(0, 1500.0, 50.0)] };
+        let second = FrameState { frame: 100, fighters: vec![fighter(0, 1500.0, 49.5)] };
+        let decode = |delta: DeltaFrameState| {
+            let (decoded, _) = ServerMessage::decode(&ServerMessage::DeltaFrameState(delta).to_bytes()).unwrap();
+            let ServerMessage::DeltaFrameState(delta) = decoded else { unreachable!() };
+            delta
+        };
+
+        let state = decoder.apply(&decode(encoder.encode(&first, fields::ALL, false))).unwrap();
+        assert!(state.fighters[0].overflow);
+
+        // Damage is left out, but is still clamped
+        let delta = decode(encoder.encode(&second, fields::ALL, false));
+        assert_eq!(delta.fighters[0].0, fields::SHIELD);
+        let state = decoder.apply(&delta).unwrap();
+        assert!(state.fighters[0].overflow);
+        assert_eq!(state.fighters[0].shield_size, 49.5);
+
+        // Groups the client didn't subscribe to don't count
+        let mut encoder = DeltaEncoder::new();
+        let delta = encoder.encode(&first, fields::SHIELD, false);
+        assert!(!delta.fighters[0].1.overflow);
+        let delta = encoder.encode(&first, fields::ALL, true);
+        assert!(!delta.fighters[0].1.overflow);
+    }
+
+    #[test]
+    fn missed_messages_wait_for_keyframe() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let frame = |damage| FrameState { frame: 100, fighters: vec![fighter(0, damage, 50.0)] };
+
+        assert!(decoder.apply(&encoder.encode(&frame(1.0), fields::ALL, false)).is_some());
+        encoder.encode(&frame(2.0), fields::ALL, false);
+        assert_eq!(decoder.apply(&encoder.encode(&frame(3.0), fields::ALL, false)), None);
+
+        let mut sequence = 3;
+        loop {
+            let delta = encoder.encode(&frame(4.0), fields::ALL, false);
+            sequence += 1;
+            match decoder.apply(&delta) {
+                Some(state) => {
+                    assert!(delta.keyframe);
+                    assert_eq!(state, frame(4.0));
+                    break;
+                },
+                None => assert!(sequence <= KEYFRAME_INTERVAL),
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! LAN discovery.
+//!
+//! Clients find consoles by broadcasting a probe to UDP port 42070. Every
+//! server that receives it answers the sender with a reply datagram.
+//!
+//! Probe:
+//!
+//! ```text
+//! magic  4 bytes "RFD?"
+//! ```
+//!
+//! Reply (all integers big endian):
+//!
+//! ```text
+//! magic             4 bytes "RFD!"
+//! protocol version  u8 major, u8 minor  highest version the server supports
+//! port              u16  TCP port of the server
+//! mapping checksum  u32  same value as MappingInfoChecksum
+//! session state     u8   see SessionState
+//! nickname          u8 length + UTF-8  console nickname
+//! ```
+//!
+//! Replies may have extra bytes at the end, which should be ignored.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use crate::reader::{Reader, put_string};
+use crate::DecodeError;
+
+pub const DEFAULT_PORT: u16 = 42070;
+pub const PROBE_MAGIC: &[u8; 4] = b"RFD?";
+pub const REPLY_MAGIC: &[u8; 4] = b"RFD!";
+
+#[derive(IntoPrimitive, TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SessionState {
+    Idle,
+    Match,
+    Training,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryReply {
+    pub protocol_major: u8,
+    pub protocol_minor: u8,
+    pub port: u16,
+    pub mapping_info_checksum: u32,
+    pub session_state: SessionState,
+    pub nickname: String,
+}
+
+pub fn encode_probe(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(PROBE_MAGIC);
+}
+
+pub fn is_probe(buf: &[u8]) -> bool {
+    buf.starts_with(PROBE_MAGIC)
+}
+
+impl DiscoveryReply {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(REPLY_MAGIC);
+        buf.push(self.protocol_major);
+        buf.push(self.protocol_minor);
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        buf.extend_from_slice(&self.mapping_info_checksum.to_be_bytes());
+        buf.push(self.session_state.into());
+        put_string(buf, &self.nickname);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        if r.bytes(4)? != REPLY_MAGIC {
+            return Err(DecodeError::Invalid("not a discovery reply"));
+        }
+        Ok(Self {
+            protocol_major: r.u8()?,
+            protocol_minor: r.u8()?,
+            port: r.u16()?,
+            mapping_info_checksum: r.u32()?,
+            session_state: SessionState::try_from(r.u8()?)
+                .map_err(|_| DecodeError::Invalid("unknown session state"))?,
+            nickname: r.string()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_round_trip() {
+        let reply = DiscoveryReply {
+            protocol_major: 1,
+            protocol_minor: 2,
+            port: 42069,
+            mapping_info_checksum: 0xdeadbeef,
+            session_state: SessionState::Training,
+            nickname: "TheComet's Switch".to_string(),
+        };
+        let mut buf = Vec::new();
+        reply.encode(&mut buf);
+        assert_eq!(DiscoveryReply::decode(&buf), Ok(reply.clone()));
+
+        // Extra bytes from a later version
+        buf.push(0xaa);
+        assert_eq!(DiscoveryReply::decode(&buf), Ok(reply));
+    }
+
+    #[test]
+    fn probes_are_not_replies() {
+        let mut buf = Vec::new();
+        encode_probe(&mut buf);
+        assert!(is_probe(&buf));
+        assert_eq!(DiscoveryReply::decode(&buf), Err(DecodeError::Invalid("not a discovery reply")));
+    }
+}
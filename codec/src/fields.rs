@@ -0,0 +1,31 @@
+//! Field groups of ExtendedFighterState.
+//!
+//! Clients choose which groups they want in their Subscribe message, and
+//! every ExtendedFighterState says which groups it carries. The frame, entry
+//! ID and the flags byte are always sent.
+
+/// pos_x, pos_y
+pub const POSITION: u8 = 1 << 0;
+
+/// status_kind, motion_kind
+pub const STATUS: u8 = 1 << 1;
+
+/// damage, stock_count
+pub const DAMAGE: u8 = 1 << 2;
+
+/// shield_size
+pub const SHIELD: u8 = 1 << 3;
+
+/// hit_status, hitstun_left, hitlag_left
+pub const HIT_STATUS: u8 = 1 << 4;
+
+/// buttons, buttons_pressed, stick_x, stick_y, c_stick_x, c_stick_y
+pub const INPUTS: u8 = 1 << 5;
+
+/// motion_frame, motion_end_frame, motion_rate
+pub const ANIMATION: u8 = 1 << 6;
+
+/// speed_x, speed_y and the speeds of the kinetic energies they add up from
+pub const VELOCITY: u8 = 1 << 7;
+
+pub const ALL: u8 = POSITION | STATUS | DAMAGE | SHIELD | HIT_STATUS | INPUTS | ANIMATION | VELOCITY;
@@ -0,0 +1,168 @@
+//! Framed wire format, used from protocol version 1.2 onwards.
+//!
+//! Every message in either direction is preceded by a header:
+//!
+//! ```text
+//! message type    u8
+//! payload length  u32 (big endian)
+//! ```
+//!
+//! This lets the receiver skip messages it doesn't know and resynchronize
+//! after a malformed payload. Payloads may be longer than what the receiver
+//! decodes, any extra bytes at the end are ignored so that fields can be
+//! appended in later versions.
+
+use crate::reader::Reader;
+use crate::{ClientMessage, DecodeError, MessageType, ServerMessage};
+
+pub const HEADER_SIZE: usize = 5;
+
+/// Longest payload either side accepts. No message comes close, a longer
+/// length means the stream can't be trusted and the connection should be
+/// closed.
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// A complete frame at the start of a buffer
+pub struct Frame<'a> {
+    pub msg_type: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Returns the frame and the total number of bytes it occupies including
+    /// the header. Fails with DecodeError::Incomplete until the whole frame
+    /// is in the buffer, or DecodeError::Invalid if the header announces more
+    /// than MAX_PAYLOAD_SIZE.
+    pub fn decode(buf: &'a [u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let msg_type = r.u8()?;
+        let len = r.u32()? as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(DecodeError::Invalid("frame too long"));
+        }
+        let payload = r.bytes(len)?;
+        Ok((Self { msg_type: msg_type, payload: payload }, HEADER_SIZE + len))
+    }
+}
+
+/// Appends a frame header followed by the payload
+pub fn encode_frame(buf: &mut Vec<u8>, msg_type: MessageType, payload: &[u8]) {
+    buf.push(msg_type.into());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// Result of decoding a framed message. The outer error is the one of
+/// Frame::decode(), after DecodeError::Invalid there is no way to find the
+/// next frame. Otherwise the length is the size of the whole frame, which
+/// should be consumed even if the message itself failed to decode.
+pub type FramedResult<T> = Result<(Result<T, DecodeError>, usize), DecodeError>;
+
+type PayloadDecoder<T> = fn(MessageType, &[u8]) -> Result<(T, usize), DecodeError>;
+
+fn decode_message<T>(buf: &[u8], decode_payload: PayloadDecoder<T>) -> FramedResult<T> {
+    let (frame, len) = Frame::decode(buf)?;
+    let msg = match MessageType::try_from(frame.msg_type) {
+        Ok(msg_type) => match decode_payload(msg_type, frame.payload) {
+            Ok((msg, _)) => Ok(msg),
+            // The header says the payload is complete, so if the message
+            // still wants more bytes it's malformed
+            Err(DecodeError::Incomplete) => Err(DecodeError::Invalid("payload too short")),
+            Err(e) => Err(e),
+        },
+        Err(_) => Err(DecodeError::UnknownMessageType(frame.msg_type)),
+    };
+    Ok((msg, len))
+}
+
+impl ClientMessage {
+    pub fn encode_framed(&self, buf: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+        encode_frame(buf, self.message_type(), &payload);
+    }
+
+    /// Decodes one framed message from the start of the buffer
+    pub fn decode_framed(buf: &[u8]) -> FramedResult<Self> {
+        decode_message(buf, Self::decode_payload)
+    }
+}
+
+impl ServerMessage {
+    pub fn encode_framed(&self, buf: &mut Vec<u8>) {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+        encode_frame(buf, self.message_type(), &payload);
+    }
+
+    pub fn to_framed_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_framed(&mut buf);
+        buf
+    }
+
+    /// Decodes one framed message from the start of the buffer, see
+    /// ClientMessage::decode_framed()
+    pub fn decode_framed(buf: &[u8]) -> FramedResult<Self> {
+        decode_message(buf, Self::decode_payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TrainingStart;
+
+    #[test]
+    fn framed_round_trip() {
+        let msg = ServerMessage::TrainingStart(TrainingStart { stage_id: 3, p1_fighter_kind: 8, cpu_fighter_kind: 1 });
+        let bytes = msg.to_framed_bytes();
+        assert_eq!(&bytes[..HEADER_SIZE], &[MessageType::TrainingStart.into(), 0, 0, 0, 4]);
+        assert_eq!(ServerMessage::decode_framed(&bytes), Ok((Ok(msg), bytes.len())));
+
+        let msg = ClientMessage::ReplayDownloadRequest { name: "replay_1.rfr".to_string(), offset: 7 };
+        let mut bytes = Vec::new();
+        msg.encode_framed(&mut bytes);
+        assert_eq!(ClientMessage::decode_framed(&bytes), Ok((Ok(msg), bytes.len())));
+    }
+
+    #[test]
+    fn truncated_frames_are_incomplete() {
+        let bytes = ServerMessage::MappingInfoChecksum(1234).to_framed_bytes();
+        for len in 0..bytes.len() {
+            assert!(matches!(ServerMessage::decode_framed(&bytes[..len]), Err(DecodeError::Incomplete)));
+        }
+    }
+
+    #[test]
+    fn unknown_and_malformed_frames_are_skipped() {
+        let mut buf = vec![0xfe, 0, 0, 0, 3, 1, 2, 3];
+        // MappingInfoChecksum with a payload that is one byte too short
+        buf.extend_from_slice(&[MessageType::MappingInfoChecksum.into(), 0, 0, 0, 3, 1, 2, 3]);
+        ServerMessage::MatchEnd.encode_framed(&mut buf);
+
+        let (msg, len) = ServerMessage::decode_framed(&buf).unwrap();
+        assert_eq!(msg, Err(DecodeError::UnknownMessageType(0xfe)));
+        assert_eq!(len, 8);
+        let (msg, len2) = ServerMessage::decode_framed(&buf[len..]).unwrap();
+        assert_eq!(msg, Err(DecodeError::Invalid("payload too short")));
+        let (msg, _) = ServerMessage::decode_framed(&buf[len + len2..]).unwrap();
+        assert_eq!(msg, Ok(ServerMessage::MatchEnd));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut buf = vec![MessageType::MappingInfoChecksum.into()];
+        buf.extend_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes());
+        assert_eq!(ClientMessage::decode_framed(&buf), Err(DecodeError::Invalid("frame too long")));
+        buf[1..HEADER_SIZE].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(ClientMessage::decode_framed(&buf), Err(DecodeError::Invalid("frame too long")));
+    }
+
+    #[test]
+    fn extra_payload_bytes_are_ignored() {
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, MessageType::MappingInfoChecksum, &[0, 0, 1, 0, 0xaa, 0xbb]);
+        assert_eq!(ServerMessage::decode_framed(&buf), Ok((Ok(ServerMessage::MappingInfoChecksum(256)), buf.len())));
+    }
+}
@@ -0,0 +1,68 @@
+//! Wire format of the ReFramed protocol.
+//!
+//! This crate has no dependency on skyline or smash so that it can be built
+//! and tested on a PC. The plugin uses it to encode everything it sends and
+//! to parse everything it receives, and PC clients can use it to do the
+//! opposite.
+
+// Same style as the plugin: explicit field names and shifts by zero when
+// packing bits
+#![allow(clippy::redundant_field_names, clippy::identity_op)]
+
+mod reader;
+mod message;
+mod mapping_info;
+pub mod capabilities;
+pub mod discovery;
+pub mod fields;
+pub mod frame;
+pub mod delta;
+pub mod replay;
+pub mod udp;
+pub mod websocket;
+
+pub use message::{
+    MessageType,
+    ClientMessage,
+    ServerMessage,
+    PlayerInfo,
+    ClientInfo,
+    Hitbox,
+    MatchStart,
+    TrainingStart,
+    FighterState,
+    FrameState,
+    Subscription,
+    ReplayListEntry,
+};
+pub use mapping_info::MappingInfoHasher;
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ends before the message is complete. Receive more bytes
+    /// and try again.
+    Incomplete,
+    /// The first byte is not a known message type. The caller has to decide
+    /// how to resynchronize.
+    UnknownMessageType(u8),
+    /// The message type is known but isn't something this side of the
+    /// connection expects to receive.
+    UnexpectedMessageType(MessageType),
+    /// The data is malformed.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete => write!(f, "incomplete message"),
+            DecodeError::UnknownMessageType(t) => write!(f, "unknown message type {}", t),
+            DecodeError::UnexpectedMessageType(t) => write!(f, "unexpected message type {:?}", t),
+            DecodeError::Invalid(what) => write!(f, "invalid message: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
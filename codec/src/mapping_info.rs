@@ -0,0 +1,76 @@
+use crc::{Crc, Digest, CRC_32_CKSUM};
+
+static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// Calculates the checksum sent in MappingInfoChecksum. Clients compare it
+/// against the checksum of their cached mapping info to decide whether they
+/// need to request it again.
+///
+/// Entries must be added grouped by table in the order fighter kinds, stage
+/// kinds, fighter status kinds, hit status kinds.
+pub struct MappingInfoHasher {
+    digest: Digest<'static, u32>,
+}
+
+impl MappingInfoHasher {
+    pub fn new() -> Self {
+        Self {
+            digest: CRC.digest(),
+        }
+    }
+
+    pub fn add_fighter_kind(&mut self, kind: u8, name: &str) {
+        self.digest.update(&kind.to_be_bytes());
+        self.digest.update(name.as_bytes());
+    }
+
+    pub fn add_stage_kind(&mut self, kind: u16, name: &str) {
+        self.digest.update(&kind.to_be_bytes());
+        self.digest.update(name.as_bytes());
+    }
+
+    pub fn add_fighter_status_kind(&mut self, fighter_kind: u8, status_kind: u16, name: &str) {
+        self.digest.update(&fighter_kind.to_be_bytes());
+        self.digest.update(&status_kind.to_be_bytes());
+        self.digest.update(name.as_bytes());
+    }
+
+    pub fn add_hit_status_kind(&mut self, kind: u8, name: &str) {
+        self.digest.update(&kind.to_be_bytes());
+        self.digest.update(name.as_bytes());
+    }
+
+    pub fn finish(self) -> u32 {
+        self.digest.finalize()
+    }
+}
+
+impl Default for MappingInfoHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_depends_on_contents() {
+        let mut a = MappingInfoHasher::new();
+        a.add_fighter_kind(0, "Mario");
+        a.add_stage_kind(0, "Battlefield");
+
+        let mut b = MappingInfoHasher::new();
+        b.add_fighter_kind(0, "Mario");
+        b.add_stage_kind(0, "Battlefield");
+
+        let mut c = MappingInfoHasher::new();
+        c.add_fighter_kind(0, "Mario");
+        c.add_stage_kind(1, "Battlefield");
+
+        let a = a.finish();
+        assert_eq!(a, b.finish());
+        assert_ne!(a, c.finish());
+    }
+}
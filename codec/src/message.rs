@@ -0,0 +1,1508 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use crate::reader::{Reader, put_string};
+use crate::delta::DeltaFrameState;
+use crate::{fields, DecodeError};
+
+#[derive(IntoPrimitive, TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    ProtocolVersion,
+
+    MappingInfoChecksum,
+    MappingInfoRequest,
+    MappingInfoFighterKinds,
+    MappingInfoFighterStatusKinds,
+    MappingInfoStageKinds,
+    MappingInfoHitStatusKinds,
+    MappingInfoRequestComplete,
+
+
+    MatchStart,
+    MatchResume,
+    MatchEnd,
+    TrainingStart,
+    TrainingResume,
+    TrainingReset,
+    TrainingEnd,
+
+    FighterState,
+
+    ReplayListRequest,
+    ReplayListEntry,
+    ReplayListComplete,
+    ReplayDownloadRequest,
+    ReplayDownloadChunk,
+    ReplayDownloadComplete,
+
+    ClientProtocolVersion,
+
+    Handshake,
+    HandshakeAccept,
+    HandshakeReject,
+
+    Subscribe,
+    Unsubscribe,
+
+    ExtendedFighterState,
+
+    FrameState,
+    ExtendedFrameState,
+
+    ClientListRequest,
+    ClientList,
+
+    Ping,
+    Pong,
+
+    UdpRequest,
+    UdpResponse,
+
+    DeltaFrameState,
+
+    HitboxState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerInfo {
+    pub entry_id: u8,
+    pub fighter_kind: u8,
+    pub fighter_skin: u8,
+    pub name: String,
+}
+
+/// Payload of MatchStart and MatchResume
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchStart {
+    pub stage_id: u16,
+    pub players: Vec<PlayerInfo>,
+}
+
+/// Payload of TrainingStart and TrainingResume
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingStart {
+    pub stage_id: u16,
+    pub p1_fighter_kind: u8,
+    pub cpu_fighter_kind: u8,
+}
+
+/// State of a single fighter on a single frame. Some of the values are
+/// quantized on the wire, see encode() for the exact precision.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FighterState {
+    pub frame: u32,
+    pub entry_id: u8,
+    pub pos_x: f32,
+    pub pos_y: f32,
+    pub damage: f32,
+    pub hitstun_left: f32,
+    /// Frames the fighter is frozen for. Not sent in the FighterState and
+    /// FrameState layouts of protocol 1.1.
+    pub hitlag_left: u8,
+    pub shield_size: f32,
+    pub status_kind: u16,
+    /// hash40 value, only the lower 40 bits are sent
+    pub motion_kind: u64,
+    pub hit_status: u8,
+    pub stock_count: u8,
+    pub attack_connected: bool,
+    pub facing_right: bool,
+    pub opponent_in_hitlag: bool,
+    /// Set if damage, shield_size or hitstun_left didn't fit their quantized
+    /// range and were clamped to it
+    pub overflow: bool,
+    /// Held buttons, a mask of CONTROL_PAD_BUTTON_* bits. The shoulder
+    /// triggers are only in here as the buttons they are mapped to (e.g.
+    /// CONTROL_PAD_BUTTON_GUARD), there are no analog trigger values.
+    pub buttons: u32,
+    /// Buttons that went down on this frame, same bits as `buttons`. What
+    /// the game calls "triggers", not the shoulder triggers.
+    pub buttons_pressed: u32,
+    /// Sticks go from -1.0 to 1.0 and are sent with 8 bits of precision
+    pub stick_x: f32,
+    pub stick_y: f32,
+    pub c_stick_x: f32,
+    pub c_stick_y: f32,
+    /// Where the fighter is within the animation of motion_kind, in frames.
+    /// Not necessarily whole numbers, see motion_rate.
+    pub motion_frame: f32,
+    pub motion_end_frame: f32,
+    /// Animation frames advanced per game frame
+    pub motion_rate: f32,
+    /// Total speed in units per frame, the sum of the kinetic energies below
+    pub speed_x: f32,
+    pub speed_y: f32,
+    /// Movement the player controls, e.g. walking and air drift
+    pub control_speed_x: f32,
+    pub control_speed_y: f32,
+    pub gravity_speed_x: f32,
+    pub gravity_speed_y: f32,
+    /// Knockback
+    pub damage_speed_x: f32,
+    pub damage_speed_y: f32,
+    /// Movement that is part of the animation
+    pub motion_speed_x: f32,
+    pub motion_speed_y: f32,
+}
+
+/// States of all fighters on one frame. The frame of the fighter states is
+/// only sent once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameState {
+    pub frame: u32,
+    pub fighters: Vec<FighterState>,
+}
+
+/// A client connected to the server, see ClientList
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// Unique for as long as the server runs
+    pub id: u32,
+    /// IP and port as seen by the server
+    pub address: String,
+    /// Seconds since unix epoch, 0 if unknown
+    pub connected_at: u64,
+    pub capabilities: u32,
+    pub framed: bool,
+    pub subscribed: bool,
+}
+
+/// Payload of Subscribe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription {
+    /// Bit n is set if the fighter with entry ID n should be sent
+    pub entries: u8,
+    /// Field groups to send, see fields.rs. Only clients with the
+    /// EXTENDED_FIGHTER_FIELDS capability can choose, everyone else always
+    /// gets the complete FighterState.
+    pub fields: u8,
+    /// Only every nth state of each fighter is sent. 0 and 1 both mean
+    /// every frame.
+    pub decimation: u8,
+}
+
+impl Subscription {
+    /// Everything, every frame. This is what protocol 1.1 clients get.
+    pub fn all() -> Self {
+        Self {
+            entries: 0xff,
+            fields: fields::ALL,
+            decimation: 1,
+        }
+    }
+
+    pub fn wants_entry(&self, entry_id: u8) -> bool {
+        entry_id < 8 && self.entries & (1 << entry_id) != 0
+    }
+}
+
+/// An active hitbox of a fighter, see HitboxState
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hitbox {
+    /// ID the move's script gave the hitbox
+    pub id: u8,
+    /// hash40 of the collision attribute, e.g. collision_attr_elec. Only the
+    /// lower 40 bits are sent, same as motion_kind.
+    pub kind: u64,
+    /// hash40 of the bone the hitbox follows
+    pub bone: u64,
+    /// Where the bone is on the stage on this frame
+    pub bone_x: f32,
+    pub bone_y: f32,
+    /// Offset from the bone in the bone's coordinate system
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub offset_z: f32,
+    pub size: f32,
+    pub damage: f32,
+    /// Degrees, or one of the special angles such as 361 (sakurai angle)
+    pub angle: u16,
+    pub knockback_growth: u16,
+    /// Set knockback, 0 if the knockback depends on damage
+    pub fixed_knockback: u16,
+    pub base_knockback: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayListEntry {
+    pub name: String,
+    pub size: u32,
+    pub timestamp: u64,
+    pub mapping_info_checksum: u32,
+    pub match_start: MatchStart,
+}
+
+/// Messages sent from a client to the server. Most requests are a single
+/// message type byte.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// Protocol 1.1 clients only send the message type and expect the server
+    /// to answer with 1.1
+    ProtocolVersion,
+    /// Newer clients report the highest version they support. The reply is
+    /// always unframed, and if the server answers with 1.2 or later every
+    /// message after that is framed (see frame.rs).
+    ClientProtocolVersion { major: u8, minor: u8 },
+    /// Replaces ClientProtocolVersion. The client announces the range of
+    /// versions it can speak and the capabilities it supports (see
+    /// capabilities.rs), the server replies with HandshakeAccept or
+    /// HandshakeReject. Like ProtocolVersion, the reply is always unframed.
+    Handshake { min_major: u8, min_minor: u8, max_major: u8, max_minor: u8, capabilities: u32 },
+    MappingInfoChecksum,
+    MappingInfoRequest,
+    /// Protocol 1.1 clients send these after downloading the mapping info,
+    /// they act like Subscribe
+    MatchResume,
+    TrainingResume,
+    /// Start receiving match and training events. The server first sends
+    /// MatchResume or TrainingResume if one is running. Nothing but replies
+    /// to requests is sent before this. Subscribing again replaces the
+    /// previous subscription.
+    Subscribe(Subscription),
+    Unsubscribe,
+    ReplayListRequest,
+    ReplayDownloadRequest { name: String, offset: u32 },
+    /// Asks for every connected client including the one asking, for admin
+    /// and debugging tools
+    ClientListRequest,
+    /// Answered with a Pong carrying the same timestamp. The timestamp is
+    /// whatever the client wants to measure the round trip time with.
+    Ping { timestamp: u64 },
+    /// Answer to a Ping from the server, see capabilities::HEARTBEAT
+    Pong { timestamp: u64 },
+    /// Asks for frame states to be sent as datagrams to this UDP port on
+    /// the client's address instead of over TCP, see udp.rs. Everything else
+    /// stays on TCP. Port 0 goes back to TCP. Only for framed connections.
+    UdpRequest { port: u16 },
+}
+
+/// Messages sent from the server to clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    ProtocolVersion { major: u8, minor: u8 },
+    /// The version the server picked and the capabilities enabled for this
+    /// connection, which is a subset of what the client asked for
+    HandshakeAccept { major: u8, minor: u8, capabilities: u32 },
+    /// None of the versions the client supports are supported by the server.
+    /// The server closes the connection after sending this.
+    HandshakeReject { reason: String },
+
+    MappingInfoChecksum(u32),
+    /// First reply to a mapping info request, carries the checksum of the
+    /// mapping info that follows
+    MappingInfoRequest(u32),
+    MappingInfoFighterKind { fighter_kind: u8, name: String },
+    MappingInfoFighterStatusKind { fighter_kind: u8, status_kind: u16, name: String },
+    MappingInfoStageKind { stage_kind: u16, name: String },
+    MappingInfoHitStatusKind { hit_status_kind: u8, name: String },
+    MappingInfoRequestComplete,
+
+    MatchStart(MatchStart),
+    MatchResume(MatchStart),
+    MatchEnd,
+    TrainingStart(TrainingStart),
+    TrainingResume(TrainingStart),
+    TrainingReset,
+    TrainingEnd,
+
+    FighterState(FighterState),
+    /// Sent instead of FighterState to clients with the
+    /// EXTENDED_FIGHTER_FIELDS capability. Only the field groups in `fields`
+    /// are on the wire, the others decode to 0/false. Damage, shield and
+    /// hitstun are sent as they are if `full_precision` is set, see
+    /// capabilities::FULL_PRECISION.
+    ExtendedFighterState { fields: u8, full_precision: bool, state: FighterState },
+    /// All fighters of a frame in one message. Protocol 1.1 clients get
+    /// a FighterState per fighter instead.
+    FrameState(FrameState),
+    /// FrameState with the fighters in the ExtendedFighterState layout
+    ExtendedFrameState { fields: u8, full_precision: bool, state: FrameState },
+    /// Sent instead of the other frame states to clients with the
+    /// COMPRESSION capability, see delta.rs
+    DeltaFrameState(DeltaFrameState),
+    /// Hitboxes of one fighter, sent to clients with the HITBOXES capability
+    /// before the frame state of the same frame. Only sent while the fighter
+    /// has any, at most 255.
+    HitboxState { frame: u32, entry_id: u8, hitboxes: Vec<Hitbox> },
+
+    /// Reply to ClientListRequest, at most 255 clients
+    ClientList(Vec<ClientInfo>),
+
+    /// Sent to clients with capabilities::HEARTBEAT when nothing was received
+    /// from them for a while. The timestamp is in milliseconds since the unix
+    /// epoch by the console's clock.
+    Ping { timestamp: u64 },
+    /// Answer to a Ping from the client, with the client's timestamp
+    Pong { timestamp: u64 },
+    /// Reply to UdpRequest with the server's UDP port the datagrams are sent
+    /// from. 0 if frame states are sent over TCP, because the client asked
+    /// for it or because UDP isn't available.
+    UdpResponse { port: u16 },
+
+    ReplayListEntry(ReplayListEntry),
+    ReplayListComplete,
+    ReplayDownloadChunk { offset: u32, data: Vec<u8> },
+    /// A size of 0 means the download failed
+    ReplayDownloadComplete { size: u32 },
+}
+
+fn read_message_type(r: &mut Reader) -> Result<MessageType, DecodeError> {
+    let byte = r.u8()?;
+    MessageType::try_from(byte).map_err(|_| DecodeError::UnknownMessageType(byte))
+}
+
+impl ClientMessage {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            ClientMessage::ProtocolVersion => MessageType::ProtocolVersion,
+            ClientMessage::ClientProtocolVersion { .. } => MessageType::ClientProtocolVersion,
+            ClientMessage::Handshake { .. } => MessageType::Handshake,
+            ClientMessage::MappingInfoChecksum => MessageType::MappingInfoChecksum,
+            ClientMessage::MappingInfoRequest => MessageType::MappingInfoRequest,
+            ClientMessage::MatchResume => MessageType::MatchResume,
+            ClientMessage::TrainingResume => MessageType::TrainingResume,
+            ClientMessage::Subscribe(_) => MessageType::Subscribe,
+            ClientMessage::Unsubscribe => MessageType::Unsubscribe,
+            ClientMessage::ReplayListRequest => MessageType::ReplayListRequest,
+            ClientMessage::ReplayDownloadRequest { .. } => MessageType::ReplayDownloadRequest,
+            ClientMessage::ClientListRequest => MessageType::ClientListRequest,
+            ClientMessage::Ping { .. } => MessageType::Ping,
+            ClientMessage::Pong { .. } => MessageType::Pong,
+            ClientMessage::UdpRequest { .. } => MessageType::UdpRequest,
+        }
+    }
+
+    pub fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            ClientMessage::ClientProtocolVersion { major, minor } => {
+                buf.push(*major);
+                buf.push(*minor);
+            },
+            ClientMessage::Handshake { min_major, min_minor, max_major, max_minor, capabilities } => {
+                buf.push(*min_major);
+                buf.push(*min_minor);
+                buf.push(*max_major);
+                buf.push(*max_minor);
+                buf.extend_from_slice(&capabilities.to_be_bytes());
+            },
+            ClientMessage::Subscribe(subscription) => {
+                buf.push(subscription.entries);
+                buf.push(subscription.fields);
+                buf.push(subscription.decimation);
+            },
+            ClientMessage::ReplayDownloadRequest { name, offset } => {
+                put_string(buf, name);
+                buf.extend_from_slice(&offset.to_be_bytes());
+            },
+            ClientMessage::Ping { timestamp } |
+            ClientMessage::Pong { timestamp } => {
+                buf.extend_from_slice(&timestamp.to_be_bytes());
+            },
+            ClientMessage::UdpRequest { port } => {
+                buf.extend_from_slice(&port.to_be_bytes());
+            },
+            _ => {},
+        }
+    }
+
+    /// Appends the message type followed by the payload
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.message_type().into());
+        self.encode_payload(buf);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Decodes one message from the start of the buffer and returns it along
+    /// with the number of bytes it occupied
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let msg_type = read_message_type(&mut r)?;
+        let msg = Self::decode_payload_from(msg_type, &mut r)?;
+        Ok((msg, r.position()))
+    }
+
+    /// Decodes a payload whose message type was already read
+    pub fn decode_payload(msg_type: MessageType, payload: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(payload);
+        let msg = Self::decode_payload_from(msg_type, &mut r)?;
+        Ok((msg, r.position()))
+    }
+
+    fn decode_payload_from(msg_type: MessageType, r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match msg_type {
+            MessageType::ProtocolVersion => ClientMessage::ProtocolVersion,
+            MessageType::ClientProtocolVersion => ClientMessage::ClientProtocolVersion {
+                major: r.u8()?,
+                minor: r.u8()?,
+            },
+            MessageType::Handshake => ClientMessage::Handshake {
+                min_major: r.u8()?,
+                min_minor: r.u8()?,
+                max_major: r.u8()?,
+                max_minor: r.u8()?,
+                capabilities: r.u32()?,
+            },
+            MessageType::MappingInfoChecksum => ClientMessage::MappingInfoChecksum,
+            MessageType::MappingInfoRequest => ClientMessage::MappingInfoRequest,
+            MessageType::MatchResume => ClientMessage::MatchResume,
+            MessageType::TrainingResume => ClientMessage::TrainingResume,
+            MessageType::Subscribe => ClientMessage::Subscribe(Subscription {
+                entries: r.u8()?,
+                fields: r.u8()?,
+                decimation: r.u8()?,
+            }),
+            MessageType::Unsubscribe => ClientMessage::Unsubscribe,
+            MessageType::ReplayListRequest => ClientMessage::ReplayListRequest,
+            MessageType::ReplayDownloadRequest => ClientMessage::ReplayDownloadRequest {
+                name: r.string()?,
+                offset: r.u32()?,
+            },
+            MessageType::ClientListRequest => ClientMessage::ClientListRequest,
+            MessageType::Ping => ClientMessage::Ping { timestamp: r.u64()? },
+            MessageType::Pong => ClientMessage::Pong { timestamp: r.u64()? },
+            MessageType::UdpRequest => ClientMessage::UdpRequest { port: r.u16()? },
+            other => return Err(DecodeError::UnexpectedMessageType(other)),
+        })
+    }
+}
+
+impl MatchStart {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.stage_id.to_be_bytes());
+        buf.push(self.players.len() as u8);
+        for player in self.players.iter() {
+            buf.push(player.entry_id);
+            buf.push(player.fighter_kind);
+            buf.push(player.fighter_skin);
+        }
+        for player in self.players.iter() {
+            put_string(buf, &player.name);
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let stage_id = r.u16()?;
+        let count = r.u8()? as usize;
+        let mut players = Vec::with_capacity(count);
+        for _ in 0..count {
+            players.push(PlayerInfo {
+                entry_id: r.u8()?,
+                fighter_kind: r.u8()?,
+                fighter_skin: r.u8()?,
+                name: String::new(),
+            });
+        }
+        for player in players.iter_mut() {
+            player.name = r.string()?;
+        }
+        Ok(Self {
+            stage_id: stage_id,
+            players: players,
+        })
+    }
+}
+
+impl TrainingStart {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.stage_id.to_be_bytes());
+        buf.push(self.p1_fighter_kind);
+        buf.push(self.cpu_fighter_kind);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            stage_id: r.u16()?,
+            p1_fighter_kind: r.u8()?,
+            cpu_fighter_kind: r.u8()?,
+        })
+    }
+}
+
+// Bits of the flags byte that aren't fighter state
+const FLAG_OVERFLOW: u8 = 1 << 3;
+const FLAG_FULL_PRECISION: u8 = 1 << 4;
+
+const DAMAGE_SCALE: f32 = 50.0;
+const SHIELD_SCALE: f32 = 200.0;
+const HITSTUN_SCALE: f32 = 100.0;
+
+// hash40 values only use 40 bits (5 bytes)
+fn read_hash40(r: &mut Reader) -> Result<u64, DecodeError> {
+    let m = r.bytes(5)?;
+    Ok(u64::from_be_bytes([0, 0, 0, m[0], m[1], m[2], m[3], m[4]]))
+}
+
+const STICK_SCALE: f32 = 127.0;
+
+fn stick_i8(value: f32) -> i8 {
+    (value * STICK_SCALE).round() as i8
+}
+
+// Clamps to the range of u16, the flag is set if it had to
+fn quantize(value: f32, scale: f32) -> (u16, bool) {
+    let scaled = value * scale;
+    (scaled as u16, !(0.0..=u16::MAX as f32).contains(&scaled))
+}
+
+impl FighterState {
+    // We don't really need to know damage beyond 0.02% accuracy and the upper
+    // limit is 999.99%, so multiplying it by 50 lets us store it in one u16
+    fn damage_u16(&self) -> (u16, bool) {
+        quantize(self.damage, DAMAGE_SCALE)
+    }
+
+    // Shield sizes seem to be around 50ish -> 10000 max leaves some room
+    fn shield_u16(&self) -> (u16, bool) {
+        quantize(self.shield_size, SHIELD_SCALE)
+    }
+
+    // Can't think of any move with hitstun over 1 second (60)
+    // 60*100 = 60000
+    fn hitstun_u16(&self) -> (u16, bool) {
+        quantize(self.hitstun_left, HITSTUN_SCALE)
+    }
+
+    // Whether quantizing the values of the field groups clamps any of them
+    pub(crate) fn overflows(&self, fields: u8) -> bool {
+        (fields & fields::DAMAGE != 0 && self.damage_u16().1)
+            || (fields & fields::SHIELD != 0 && self.shield_u16().1)
+            || (fields & fields::HIT_STATUS != 0 && self.hitstun_u16().1)
+    }
+
+    // Full precision values are never clamped
+    fn wire_flags(&self, fields: u8, full_precision: bool) -> u8 {
+        if full_precision {
+            self.flags() | FLAG_FULL_PRECISION
+        } else if self.overflows(fields) {
+            self.flags() | FLAG_OVERFLOW
+        } else {
+            self.flags()
+        }
+    }
+
+    // In the order they are sent in
+    fn speeds(&self) -> [f32; 10] {
+        [
+            self.speed_x, self.speed_y,
+            self.control_speed_x, self.control_speed_y,
+            self.gravity_speed_x, self.gravity_speed_y,
+            self.damage_speed_x, self.damage_speed_y,
+            self.motion_speed_x, self.motion_speed_y,
+        ]
+    }
+
+    // Motion kinds are hash40 values which use 40 bits (5 bytes)
+    fn motion_bytes(&self) -> [u8; 5] {
+        let motion = self.motion_kind.to_be_bytes();
+        [motion[3], motion[4], motion[5], motion[6], motion[7]]
+    }
+
+    // Booleans can be combined into a single u8
+    fn flags(&self) -> u8 {
+        ((self.attack_connected as u8) << 0)
+      | ((self.facing_right as u8) << 1)
+      | ((self.opponent_in_hitlag as u8) << 2)
+      | ((self.overflow as u8) << 3)
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.attack_connected = flags & (1 << 0) != 0;
+        self.facing_right = flags & (1 << 1) != 0;
+        self.opponent_in_hitlag = flags & (1 << 2) != 0;
+        self.overflow = flags & FLAG_OVERFLOW != 0;
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.frame.to_be_bytes());
+        self.encode_body(buf);
+    }
+
+    // Everything but the frame, which FrameState only sends once
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        buf.push(self.entry_id);
+        buf.extend_from_slice(&self.pos_x.to_be_bytes());
+        buf.extend_from_slice(&self.pos_y.to_be_bytes());
+        buf.extend_from_slice(&self.damage_u16().0.to_be_bytes());
+        buf.extend_from_slice(&self.hitstun_u16().0.to_be_bytes());
+        buf.extend_from_slice(&self.shield_u16().0.to_be_bytes());
+        buf.extend_from_slice(&self.status_kind.to_be_bytes());
+        buf.extend_from_slice(&self.motion_bytes());
+        buf.push(self.hit_status);
+        buf.push(self.stock_count);
+        buf.push(self.wire_flags(fields::ALL, false));
+    }
+
+    /// Same precision as encode() unless `full_precision` is set, but only
+    /// the field groups in `fields`
+    fn encode_extended(&self, fields: u8, full_precision: bool, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.frame.to_be_bytes());
+        self.encode_extended_body(fields, full_precision, buf);
+    }
+
+    pub(crate) fn encode_extended_body(&self, fields: u8, full_precision: bool, buf: &mut Vec<u8>) {
+        buf.push(self.entry_id);
+        buf.push(fields);
+        buf.push(self.wire_flags(fields, full_precision));
+        self.encode_fields(fields, full_precision, buf);
+    }
+
+    // Only the values of the field groups, without entry ID and flags
+    pub(crate) fn encode_fields(&self, fields: u8, full_precision: bool, buf: &mut Vec<u8>) {
+        if fields & fields::POSITION != 0 {
+            buf.extend_from_slice(&self.pos_x.to_be_bytes());
+            buf.extend_from_slice(&self.pos_y.to_be_bytes());
+        }
+        if fields & fields::STATUS != 0 {
+            buf.extend_from_slice(&self.status_kind.to_be_bytes());
+            buf.extend_from_slice(&self.motion_bytes());
+        }
+        if fields & fields::DAMAGE != 0 {
+            if full_precision {
+                buf.extend_from_slice(&self.damage.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&self.damage_u16().0.to_be_bytes());
+            }
+            buf.push(self.stock_count);
+        }
+        if fields & fields::SHIELD != 0 {
+            if full_precision {
+                buf.extend_from_slice(&self.shield_size.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&self.shield_u16().0.to_be_bytes());
+            }
+        }
+        if fields & fields::HIT_STATUS != 0 {
+            buf.push(self.hit_status);
+            if full_precision {
+                buf.extend_from_slice(&self.hitstun_left.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&self.hitstun_u16().0.to_be_bytes());
+            }
+            buf.push(self.hitlag_left);
+        }
+        if fields & fields::INPUTS != 0 {
+            buf.extend_from_slice(&self.buttons.to_be_bytes());
+            buf.extend_from_slice(&self.buttons_pressed.to_be_bytes());
+            for stick in [self.stick_x, self.stick_y, self.c_stick_x, self.c_stick_y] {
+                buf.push(stick_i8(stick) as u8);
+            }
+        }
+        if fields & fields::ANIMATION != 0 {
+            buf.extend_from_slice(&self.motion_frame.to_be_bytes());
+            buf.extend_from_slice(&self.motion_end_frame.to_be_bytes());
+            buf.extend_from_slice(&self.motion_rate.to_be_bytes());
+        }
+        if fields & fields::VELOCITY != 0 {
+            for speed in self.speeds() {
+                buf.extend_from_slice(&speed.to_be_bytes());
+            }
+        }
+    }
+
+    fn decode_extended(r: &mut Reader) -> Result<(u8, bool, Self), DecodeError> {
+        let frame = r.u32()?;
+        Self::decode_extended_body(frame, r)
+    }
+
+    // Also returns the field groups and whether the values are full precision
+    pub(crate) fn decode_extended_body(frame: u32, r: &mut Reader) -> Result<(u8, bool, Self), DecodeError> {
+        let mut state = Self {
+            frame: frame,
+            entry_id: r.u8()?,
+            ..Default::default()
+        };
+        let fields = r.u8()?;
+        let flags = r.u8()?;
+        let full_precision = flags & FLAG_FULL_PRECISION != 0;
+        state.set_flags(flags);
+        if fields & fields::POSITION != 0 {
+            state.pos_x = r.f32()?;
+            state.pos_y = r.f32()?;
+        }
+        if fields & fields::STATUS != 0 {
+            state.status_kind = r.u16()?;
+            state.motion_kind = read_hash40(r)?;
+        }
+        if fields & fields::DAMAGE != 0 {
+            state.damage = Self::read_value(r, full_precision, DAMAGE_SCALE)?;
+            state.stock_count = r.u8()?;
+        }
+        if fields & fields::SHIELD != 0 {
+            state.shield_size = Self::read_value(r, full_precision, SHIELD_SCALE)?;
+        }
+        if fields & fields::HIT_STATUS != 0 {
+            state.hit_status = r.u8()?;
+            state.hitstun_left = Self::read_value(r, full_precision, HITSTUN_SCALE)?;
+            state.hitlag_left = r.u8()?;
+        }
+        if fields & fields::INPUTS != 0 {
+            state.buttons = r.u32()?;
+            state.buttons_pressed = r.u32()?;
+            state.stick_x = Self::read_stick(r)?;
+            state.stick_y = Self::read_stick(r)?;
+            state.c_stick_x = Self::read_stick(r)?;
+            state.c_stick_y = Self::read_stick(r)?;
+        }
+        if fields & fields::ANIMATION != 0 {
+            state.motion_frame = r.f32()?;
+            state.motion_end_frame = r.f32()?;
+            state.motion_rate = r.f32()?;
+        }
+        if fields & fields::VELOCITY != 0 {
+            state.speed_x = r.f32()?;
+            state.speed_y = r.f32()?;
+            state.control_speed_x = r.f32()?;
+            state.control_speed_y = r.f32()?;
+            state.gravity_speed_x = r.f32()?;
+            state.gravity_speed_y = r.f32()?;
+            state.damage_speed_x = r.f32()?;
+            state.damage_speed_y = r.f32()?;
+            state.motion_speed_x = r.f32()?;
+            state.motion_speed_y = r.f32()?;
+        }
+        Ok((fields, full_precision, state))
+    }
+
+    fn read_stick(r: &mut Reader) -> Result<f32, DecodeError> {
+        Ok(r.u8()? as i8 as f32 / STICK_SCALE)
+    }
+
+    fn read_value(r: &mut Reader, full_precision: bool, scale: f32) -> Result<f32, DecodeError> {
+        if full_precision {
+            r.f32()
+        } else {
+            Ok(r.u16()? as f32 / scale)
+        }
+    }
+
+    /// Takes the values of the field groups in `fields` and the flags from
+    /// `other`
+    pub(crate) fn copy_fields(&mut self, other: &Self, fields: u8) {
+        self.frame = other.frame;
+        self.set_flags(other.flags());
+        if fields & fields::POSITION != 0 {
+            self.pos_x = other.pos_x;
+            self.pos_y = other.pos_y;
+        }
+        if fields & fields::STATUS != 0 {
+            self.status_kind = other.status_kind;
+            self.motion_kind = other.motion_kind;
+        }
+        if fields & fields::DAMAGE != 0 {
+            self.damage = other.damage;
+            self.stock_count = other.stock_count;
+        }
+        if fields & fields::SHIELD != 0 {
+            self.shield_size = other.shield_size;
+        }
+        if fields & fields::HIT_STATUS != 0 {
+            self.hit_status = other.hit_status;
+            self.hitstun_left = other.hitstun_left;
+            self.hitlag_left = other.hitlag_left;
+        }
+        if fields & fields::INPUTS != 0 {
+            self.buttons = other.buttons;
+            self.buttons_pressed = other.buttons_pressed;
+            self.stick_x = other.stick_x;
+            self.stick_y = other.stick_y;
+            self.c_stick_x = other.c_stick_x;
+            self.c_stick_y = other.c_stick_y;
+        }
+        if fields & fields::ANIMATION != 0 {
+            self.motion_frame = other.motion_frame;
+            self.motion_end_frame = other.motion_end_frame;
+            self.motion_rate = other.motion_rate;
+        }
+        if fields & fields::VELOCITY != 0 {
+            self.speed_x = other.speed_x;
+            self.speed_y = other.speed_y;
+            self.control_speed_x = other.control_speed_x;
+            self.control_speed_y = other.control_speed_y;
+            self.gravity_speed_x = other.gravity_speed_x;
+            self.gravity_speed_y = other.gravity_speed_y;
+            self.damage_speed_x = other.damage_speed_x;
+            self.damage_speed_y = other.damage_speed_y;
+            self.motion_speed_x = other.motion_speed_x;
+            self.motion_speed_y = other.motion_speed_y;
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let frame = r.u32()?;
+        Self::decode_body(frame, r)
+    }
+
+    fn decode_body(frame: u32, r: &mut Reader) -> Result<Self, DecodeError> {
+        let entry_id = r.u8()?;
+        let pos_x = r.f32()?;
+        let pos_y = r.f32()?;
+        let damage = r.u16()? as f32 / DAMAGE_SCALE;
+        let hitstun_left = r.u16()? as f32 / HITSTUN_SCALE;
+        let shield_size = r.u16()? as f32 / SHIELD_SCALE;
+        let status_kind = r.u16()?;
+        let motion_kind = read_hash40(r)?;
+        let hit_status = r.u8()?;
+        let stock_count = r.u8()?;
+        let flags = r.u8()?;
+        let mut state = Self {
+            frame: frame,
+            entry_id: entry_id,
+            pos_x: pos_x,
+            pos_y: pos_y,
+            damage: damage,
+            hitstun_left: hitstun_left,
+            shield_size: shield_size,
+            status_kind: status_kind,
+            motion_kind: motion_kind,
+            hit_status: hit_status,
+            stock_count: stock_count,
+            ..Default::default()
+        };
+        state.set_flags(flags);
+        Ok(state)
+    }
+}
+
+impl FrameState {
+    fn encode(&self, extended_fields: Option<u8>, full_precision: bool, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.frame.to_be_bytes());
+        buf.push(self.fighters.len() as u8);
+        for fighter in self.fighters.iter() {
+            match extended_fields {
+                Some(fields) => fighter.encode_extended_body(fields, full_precision, buf),
+                None => fighter.encode_body(buf),
+            }
+        }
+    }
+
+    // Also returns the field groups and precision of the extended layout.
+    // Every fighter carries its own, the ones of the last fighter are
+    // returned.
+    fn decode(r: &mut Reader, extended: bool) -> Result<(u8, bool, Self), DecodeError> {
+        let frame = r.u32()?;
+        let count = r.u8()? as usize;
+        let mut fields = fields::ALL;
+        let mut full_precision = false;
+        let mut fighters = Vec::with_capacity(count);
+        for _ in 0..count {
+            if extended {
+                let (f, precision, fighter) = FighterState::decode_extended_body(frame, r)?;
+                fields = f;
+                full_precision = precision;
+                fighters.push(fighter);
+            } else {
+                fighters.push(FighterState::decode_body(frame, r)?);
+            }
+        }
+        Ok((fields, full_precision, Self {
+            frame: frame,
+            fighters: fighters,
+        }))
+    }
+}
+
+impl Hitbox {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.id);
+        buf.extend_from_slice(&self.kind.to_be_bytes()[3..]);
+        buf.extend_from_slice(&self.bone.to_be_bytes()[3..]);
+        for value in [self.bone_x, self.bone_y, self.offset_x, self.offset_y, self.offset_z, self.size, self.damage] {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        for value in [self.angle, self.knockback_growth, self.fixed_knockback, self.base_knockback] {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            id: r.u8()?,
+            kind: read_hash40(r)?,
+            bone: read_hash40(r)?,
+            bone_x: r.f32()?,
+            bone_y: r.f32()?,
+            offset_x: r.f32()?,
+            offset_y: r.f32()?,
+            offset_z: r.f32()?,
+            size: r.f32()?,
+            damage: r.f32()?,
+            angle: r.u16()?,
+            knockback_growth: r.u16()?,
+            fixed_knockback: r.u16()?,
+            base_knockback: r.u16()?,
+        })
+    }
+}
+
+impl ClientInfo {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let flags =
+            ((self.framed as u8) << 0)
+          | ((self.subscribed as u8) << 1);
+
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        put_string(buf, &self.address);
+        buf.extend_from_slice(&self.connected_at.to_be_bytes());
+        buf.extend_from_slice(&self.capabilities.to_be_bytes());
+        buf.push(flags);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let id = r.u32()?;
+        let address = r.string()?;
+        let connected_at = r.u64()?;
+        let capabilities = r.u32()?;
+        let flags = r.u8()?;
+        Ok(Self {
+            id: id,
+            address: address,
+            connected_at: connected_at,
+            capabilities: capabilities,
+            framed: flags & (1 << 0) != 0,
+            subscribed: flags & (1 << 1) != 0,
+        })
+    }
+}
+
+impl ReplayListEntry {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_string(buf, &self.name);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.mapping_info_checksum.to_be_bytes());
+        self.match_start.encode(buf);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            name: r.string()?,
+            size: r.u32()?,
+            timestamp: r.u64()?,
+            mapping_info_checksum: r.u32()?,
+            match_start: MatchStart::decode(r)?,
+        })
+    }
+}
+
+impl ServerMessage {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            ServerMessage::ProtocolVersion { .. } => MessageType::ProtocolVersion,
+            ServerMessage::HandshakeAccept { .. } => MessageType::HandshakeAccept,
+            ServerMessage::HandshakeReject { .. } => MessageType::HandshakeReject,
+            ServerMessage::MappingInfoChecksum(_) => MessageType::MappingInfoChecksum,
+            ServerMessage::MappingInfoRequest(_) => MessageType::MappingInfoRequest,
+            ServerMessage::MappingInfoFighterKind { .. } => MessageType::MappingInfoFighterKinds,
+            ServerMessage::MappingInfoFighterStatusKind { .. } => MessageType::MappingInfoFighterStatusKinds,
+            ServerMessage::MappingInfoStageKind { .. } => MessageType::MappingInfoStageKinds,
+            ServerMessage::MappingInfoHitStatusKind { .. } => MessageType::MappingInfoHitStatusKinds,
+            ServerMessage::MappingInfoRequestComplete => MessageType::MappingInfoRequestComplete,
+            ServerMessage::MatchStart(_) => MessageType::MatchStart,
+            ServerMessage::MatchResume(_) => MessageType::MatchResume,
+            ServerMessage::MatchEnd => MessageType::MatchEnd,
+            ServerMessage::TrainingStart(_) => MessageType::TrainingStart,
+            ServerMessage::TrainingResume(_) => MessageType::TrainingResume,
+            ServerMessage::TrainingReset => MessageType::TrainingReset,
+            ServerMessage::TrainingEnd => MessageType::TrainingEnd,
+            ServerMessage::FighterState(_) => MessageType::FighterState,
+            ServerMessage::ExtendedFighterState { .. } => MessageType::ExtendedFighterState,
+            ServerMessage::FrameState(_) => MessageType::FrameState,
+            ServerMessage::ExtendedFrameState { .. } => MessageType::ExtendedFrameState,
+            ServerMessage::DeltaFrameState(_) => MessageType::DeltaFrameState,
+            ServerMessage::HitboxState { .. } => MessageType::HitboxState,
+            ServerMessage::ClientList(_) => MessageType::ClientList,
+            ServerMessage::Ping { .. } => MessageType::Ping,
+            ServerMessage::Pong { .. } => MessageType::Pong,
+            ServerMessage::UdpResponse { .. } => MessageType::UdpResponse,
+            ServerMessage::ReplayListEntry(_) => MessageType::ReplayListEntry,
+            ServerMessage::ReplayListComplete => MessageType::ReplayListComplete,
+            ServerMessage::ReplayDownloadChunk { .. } => MessageType::ReplayDownloadChunk,
+            ServerMessage::ReplayDownloadComplete { .. } => MessageType::ReplayDownloadComplete,
+        }
+    }
+
+    pub fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            ServerMessage::ProtocolVersion { major, minor } => {
+                buf.push(*major);
+                buf.push(*minor);
+            },
+            ServerMessage::HandshakeAccept { major, minor, capabilities } => {
+                buf.push(*major);
+                buf.push(*minor);
+                buf.extend_from_slice(&capabilities.to_be_bytes());
+            },
+            ServerMessage::HandshakeReject { reason } => {
+                put_string(buf, reason);
+            },
+            ServerMessage::MappingInfoChecksum(checksum) |
+            ServerMessage::MappingInfoRequest(checksum) => {
+                buf.extend_from_slice(&checksum.to_be_bytes());
+            },
+            ServerMessage::MappingInfoFighterKind { fighter_kind, name } => {
+                buf.push(*fighter_kind);
+                put_string(buf, name);
+            },
+            ServerMessage::MappingInfoFighterStatusKind { fighter_kind, status_kind, name } => {
+                buf.push(*fighter_kind);
+                buf.extend_from_slice(&status_kind.to_be_bytes());
+                put_string(buf, name);
+            },
+            ServerMessage::MappingInfoStageKind { stage_kind, name } => {
+                buf.extend_from_slice(&stage_kind.to_be_bytes());
+                put_string(buf, name);
+            },
+            ServerMessage::MappingInfoHitStatusKind { hit_status_kind, name } => {
+                buf.push(*hit_status_kind);
+                put_string(buf, name);
+            },
+            ServerMessage::MatchStart(info) |
+            ServerMessage::MatchResume(info) => info.encode(buf),
+            ServerMessage::TrainingStart(info) |
+            ServerMessage::TrainingResume(info) => info.encode(buf),
+            ServerMessage::FighterState(state) => state.encode(buf),
+            ServerMessage::ExtendedFighterState { fields, full_precision, state } =>
+                state.encode_extended(*fields, *full_precision, buf),
+            ServerMessage::FrameState(state) => state.encode(None, false, buf),
+            ServerMessage::ExtendedFrameState { fields, full_precision, state } =>
+                state.encode(Some(*fields), *full_precision, buf),
+            ServerMessage::DeltaFrameState(state) => state.encode(buf),
+            ServerMessage::HitboxState { frame, entry_id, hitboxes } => {
+                let count = hitboxes.len().min(255);
+                buf.extend_from_slice(&frame.to_be_bytes());
+                buf.push(*entry_id);
+                buf.push(count as u8);
+                for hitbox in hitboxes[..count].iter() {
+                    hitbox.encode(buf);
+                }
+            },
+            ServerMessage::ClientList(clients) => {
+                let count = clients.len().min(255);
+                buf.push(count as u8);
+                for client in clients[..count].iter() {
+                    client.encode(buf);
+                }
+            },
+            ServerMessage::Ping { timestamp } |
+            ServerMessage::Pong { timestamp } => {
+                buf.extend_from_slice(&timestamp.to_be_bytes());
+            },
+            ServerMessage::UdpResponse { port } => {
+                buf.extend_from_slice(&port.to_be_bytes());
+            },
+            ServerMessage::ReplayListEntry(entry) => entry.encode(buf),
+            ServerMessage::ReplayDownloadChunk { offset, data } => {
+                let len = data.len().min(u16::MAX as usize);
+                buf.extend_from_slice(&offset.to_be_bytes());
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+                buf.extend_from_slice(&data[..len]);
+            },
+            ServerMessage::ReplayDownloadComplete { size } => {
+                buf.extend_from_slice(&size.to_be_bytes());
+            },
+            ServerMessage::MappingInfoRequestComplete |
+            ServerMessage::MatchEnd |
+            ServerMessage::TrainingReset |
+            ServerMessage::TrainingEnd |
+            ServerMessage::ReplayListComplete => {},
+        }
+    }
+
+    /// Appends the message type followed by the payload
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.message_type().into());
+        self.encode_payload(buf);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Decodes one message from the start of the buffer and returns it along
+    /// with the number of bytes it occupied
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let msg_type = read_message_type(&mut r)?;
+        let msg = Self::decode_payload_from(msg_type, &mut r)?;
+        Ok((msg, r.position()))
+    }
+
+    /// Decodes a payload whose message type was already read
+    pub fn decode_payload(msg_type: MessageType, payload: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(payload);
+        let msg = Self::decode_payload_from(msg_type, &mut r)?;
+        Ok((msg, r.position()))
+    }
+
+    fn decode_payload_from(msg_type: MessageType, r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match msg_type {
+            MessageType::ProtocolVersion => ServerMessage::ProtocolVersion {
+                major: r.u8()?,
+                minor: r.u8()?,
+            },
+            MessageType::HandshakeAccept => ServerMessage::HandshakeAccept {
+                major: r.u8()?,
+                minor: r.u8()?,
+                capabilities: r.u32()?,
+            },
+            MessageType::HandshakeReject => ServerMessage::HandshakeReject {
+                reason: r.string()?,
+            },
+            MessageType::MappingInfoChecksum => ServerMessage::MappingInfoChecksum(r.u32()?),
+            MessageType::MappingInfoRequest => ServerMessage::MappingInfoRequest(r.u32()?),
+            MessageType::MappingInfoFighterKinds => ServerMessage::MappingInfoFighterKind {
+                fighter_kind: r.u8()?,
+                name: r.string()?,
+            },
+            MessageType::MappingInfoFighterStatusKinds => ServerMessage::MappingInfoFighterStatusKind {
+                fighter_kind: r.u8()?,
+                status_kind: r.u16()?,
+                name: r.string()?,
+            },
+            MessageType::MappingInfoStageKinds => ServerMessage::MappingInfoStageKind {
+                stage_kind: r.u16()?,
+                name: r.string()?,
+            },
+            MessageType::MappingInfoHitStatusKinds => ServerMessage::MappingInfoHitStatusKind {
+                hit_status_kind: r.u8()?,
+                name: r.string()?,
+            },
+            MessageType::MappingInfoRequestComplete => ServerMessage::MappingInfoRequestComplete,
+            MessageType::MatchStart => ServerMessage::MatchStart(MatchStart::decode(r)?),
+            MessageType::MatchResume => ServerMessage::MatchResume(MatchStart::decode(r)?),
+            MessageType::MatchEnd => ServerMessage::MatchEnd,
+            MessageType::TrainingStart => ServerMessage::TrainingStart(TrainingStart::decode(r)?),
+            MessageType::TrainingResume => ServerMessage::TrainingResume(TrainingStart::decode(r)?),
+            MessageType::TrainingReset => ServerMessage::TrainingReset,
+            MessageType::TrainingEnd => ServerMessage::TrainingEnd,
+            MessageType::FighterState => ServerMessage::FighterState(FighterState::decode(r)?),
+            MessageType::ExtendedFighterState => {
+                let (fields, full_precision, state) = FighterState::decode_extended(r)?;
+                ServerMessage::ExtendedFighterState { fields: fields, full_precision: full_precision, state: state }
+            },
+            MessageType::FrameState => ServerMessage::FrameState(FrameState::decode(r, false)?.2),
+            MessageType::ExtendedFrameState => {
+                let (fields, full_precision, state) = FrameState::decode(r, true)?;
+                ServerMessage::ExtendedFrameState { fields: fields, full_precision: full_precision, state: state }
+            },
+            MessageType::DeltaFrameState => ServerMessage::DeltaFrameState(DeltaFrameState::decode(r)?),
+            MessageType::HitboxState => {
+                let frame = r.u32()?;
+                let entry_id = r.u8()?;
+                let count = r.u8()? as usize;
+                let mut hitboxes = Vec::with_capacity(count);
+                for _ in 0..count {
+                    hitboxes.push(Hitbox::decode(r)?);
+                }
+                ServerMessage::HitboxState { frame: frame, entry_id: entry_id, hitboxes: hitboxes }
+            },
+            MessageType::ClientList => {
+                let count = r.u8()? as usize;
+                let mut clients = Vec::with_capacity(count);
+                for _ in 0..count {
+                    clients.push(ClientInfo::decode(r)?);
+                }
+                ServerMessage::ClientList(clients)
+            },
+            MessageType::Ping => ServerMessage::Ping { timestamp: r.u64()? },
+            MessageType::Pong => ServerMessage::Pong { timestamp: r.u64()? },
+            MessageType::UdpResponse => ServerMessage::UdpResponse { port: r.u16()? },
+            MessageType::ReplayListEntry => ServerMessage::ReplayListEntry(ReplayListEntry::decode(r)?),
+            MessageType::ReplayListComplete => ServerMessage::ReplayListComplete,
+            MessageType::ReplayDownloadChunk => {
+                let offset = r.u32()?;
+                let len = r.u16()? as usize;
+                ServerMessage::ReplayDownloadChunk {
+                    offset: offset,
+                    data: r.bytes(len)?.to_vec(),
+                }
+            },
+            MessageType::ReplayDownloadComplete => ServerMessage::ReplayDownloadComplete {
+                size: r.u32()?,
+            },
+            other => return Err(DecodeError::UnexpectedMessageType(other)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_start() -> MatchStart {
+        MatchStart {
+            stage_id: 0x0123,
+            players: vec![
+                PlayerInfo { entry_id: 0, fighter_kind: 8, fighter_skin: 2, name: "TheComet".to_string() },
+                PlayerInfo { entry_id: 1, fighter_kind: 84, fighter_skin: 0, name: "Player 2".to_string() },
+                PlayerInfo { entry_id: 3, fighter_kind: 20, fighter_skin: 7, name: "".to_string() },
+            ],
+        }
+    }
+
+    fn fighter_state() -> FighterState {
+        // Chosen so that they survive quantization exactly
+        FighterState {
+            frame: 25200,
+            entry_id: 1,
+            pos_x: -43.25,
+            pos_y: 12.0,
+            damage: 123.5,
+            hitstun_left: 17.25,
+            shield_size: 50.0,
+            status_kind: 872,
+            motion_kind: 0x12_3456_789a,
+            hit_status: 2,
+            stock_count: 3,
+            attack_connected: true,
+            opponent_in_hitlag: true,
+            // The rest isn't in the 1.1 layout
+            ..Default::default()
+        }
+    }
+
+    fn frame_state() -> FrameState {
+        let mut other = fighter_state();
+        other.entry_id = 3;
+        other.facing_right = true;
+        FrameState {
+            frame: 25200,
+            fighters: vec![fighter_state(), other],
+        }
+    }
+
+    fn all_server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::ProtocolVersion { major: 1, minor: 1 },
+            ServerMessage::HandshakeAccept { major: 1, minor: 2, capabilities: 0x0000_0006 },
+            ServerMessage::HandshakeReject { reason: "Unsupported protocol version".to_string() },
+            ServerMessage::MappingInfoChecksum(0xdeadbeef),
+            ServerMessage::MappingInfoRequest(0x01020304),
+            ServerMessage::MappingInfoFighterKind { fighter_kind: 8, name: "FIGHTER_KIND_PIKACHU".to_string() },
+            ServerMessage::MappingInfoFighterStatusKind { fighter_kind: 255, status_kind: 872, name: "FIGHTER_STATUS_KIND_WAIT".to_string() },
+            ServerMessage::MappingInfoStageKind { stage_kind: 107, name: "Pokemon Stadium 2".to_string() },
+            ServerMessage::MappingInfoHitStatusKind { hit_status_kind: 1, name: "HIT_STATUS_NORMAL".to_string() },
+            ServerMessage::MappingInfoRequestComplete,
+            ServerMessage::MatchStart(match_start()),
+            ServerMessage::MatchResume(match_start()),
+            ServerMessage::MatchEnd,
+            ServerMessage::TrainingStart(TrainingStart { stage_id: 3, p1_fighter_kind: 8, cpu_fighter_kind: 1 }),
+            ServerMessage::TrainingResume(TrainingStart { stage_id: 95, p1_fighter_kind: 2, cpu_fighter_kind: 3 }),
+            ServerMessage::TrainingReset,
+            ServerMessage::TrainingEnd,
+            ServerMessage::FighterState(fighter_state()),
+            ServerMessage::ExtendedFighterState { fields: fields::ALL, full_precision: false, state: fighter_state() },
+            ServerMessage::ExtendedFighterState { fields: fields::ALL, full_precision: true, state: fighter_state() },
+            ServerMessage::FrameState(frame_state()),
+            ServerMessage::ExtendedFrameState { fields: fields::ALL, full_precision: false, state: frame_state() },
+            ServerMessage::ExtendedFrameState { fields: fields::ALL, full_precision: true, state: frame_state() },
+            ServerMessage::DeltaFrameState(DeltaFrameState {
+                sequence: 61,
+                keyframe: false,
+                frame: 25200,
+                full_precision: false,
+                fighters: vec![
+                    (fields::ALL, fighter_state()),
+                    (fields::DAMAGE, FighterState { frame: 25200, entry_id: 3, damage: 4.0, stock_count: 1, ..Default::default() }),
+                ],
+            }),
+            ServerMessage::ClientList(vec![
+                ClientInfo { id: 1, address: "192.168.1.20:51234".to_string(), connected_at: 1700000000, capabilities: 0x6, framed: true, subscribed: true },
+                ClientInfo { id: 7, address: "10.0.0.3:40000".to_string(), connected_at: 0, capabilities: 0, framed: false, subscribed: false },
+            ]),
+            ServerMessage::Ping { timestamp: 1_700_000_000_123 },
+            ServerMessage::Pong { timestamp: u64::MAX },
+            ServerMessage::UdpResponse { port: 50123 },
+            ServerMessage::HitboxState {
+                frame: 25200,
+                entry_id: 1,
+                hitboxes: vec![
+                    Hitbox {
+                        id: 0,
+                        kind: 0x15_8a8b_d3bf,
+                        bone: 0x05_e4a4_c5a8,
+                        bone_x: -40.5,
+                        bone_y: 14.25,
+                        offset_x: 0.0,
+                        offset_y: 2.5,
+                        offset_z: -1.0,
+                        size: 4.5,
+                        damage: 11.2,
+                        angle: 361,
+                        knockback_growth: 100,
+                        fixed_knockback: 0,
+                        base_knockback: 30,
+                    },
+                    Hitbox { id: 3, ..Default::default() },
+                ],
+            },
+            ServerMessage::ReplayListEntry(ReplayListEntry {
+                name: "replay_1700000000.rfr".to_string(),
+                size: 123456,
+                timestamp: 1700000000,
+                mapping_info_checksum: 0xcafebabe,
+                match_start: match_start(),
+            }),
+            ServerMessage::ReplayListComplete,
+            ServerMessage::ReplayDownloadChunk { offset: 4096, data: vec![1, 2, 3, 4, 5] },
+            ServerMessage::ReplayDownloadComplete { size: 8192 },
+        ]
+    }
+
+    fn all_client_messages() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::ProtocolVersion,
+            ClientMessage::ClientProtocolVersion { major: 1, minor: 2 },
+            ClientMessage::Handshake { min_major: 1, min_minor: 1, max_major: 1, max_minor: 2, capabilities: 0xffff_ffff },
+            ClientMessage::MappingInfoChecksum,
+            ClientMessage::MappingInfoRequest,
+            ClientMessage::MatchResume,
+            ClientMessage::TrainingResume,
+            ClientMessage::Subscribe(Subscription { entries: 0b0000_0101, fields: fields::DAMAGE, decimation: 4 }),
+            ClientMessage::Unsubscribe,
+            ClientMessage::ReplayListRequest,
+            ClientMessage::ReplayDownloadRequest { name: "replay_1.rfr".to_string(), offset: 300 },
+            ClientMessage::ClientListRequest,
+            ClientMessage::Ping { timestamp: 1_700_000_000_123 },
+            ClientMessage::Pong { timestamp: 42 },
+            ClientMessage::UdpRequest { port: 0 },
+        ]
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for msg in all_server_messages() {
+            let bytes = msg.to_bytes();
+            assert_eq!(ServerMessage::decode(&bytes), Ok((msg, bytes.len())));
+        }
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for msg in all_client_messages() {
+            let bytes = msg.to_bytes();
+            assert_eq!(ClientMessage::decode(&bytes), Ok((msg, bytes.len())));
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_incomplete() {
+        for msg in all_server_messages() {
+            let bytes = msg.to_bytes();
+            for len in 0..bytes.len() {
+                assert_eq!(ServerMessage::decode(&bytes[..len]), Err(DecodeError::Incomplete), "{:?}", msg);
+            }
+        }
+        for msg in all_client_messages() {
+            let bytes = msg.to_bytes();
+            for len in 0..bytes.len() {
+                assert_eq!(ClientMessage::decode(&bytes[..len]), Err(DecodeError::Incomplete), "{:?}", msg);
+            }
+        }
+    }
+
+    #[test]
+    fn consecutive_messages_decode_in_order() {
+        let mut buf = Vec::new();
+        let msgs = all_server_messages();
+        for msg in msgs.iter() {
+            msg.encode(&mut buf);
+        }
+
+        let mut pos = 0;
+        for msg in msgs.iter() {
+            let (decoded, len) = ServerMessage::decode(&buf[pos..]).unwrap();
+            assert_eq!(&decoded, msg);
+            pos += len;
+        }
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn unknown_and_unexpected_message_types() {
+        assert_eq!(ServerMessage::decode(&[0xff]), Err(DecodeError::UnknownMessageType(0xff)));
+        assert_eq!(ClientMessage::decode(&[MessageType::FighterState.into()]),
+            Err(DecodeError::UnexpectedMessageType(MessageType::FighterState)));
+        assert_eq!(ServerMessage::decode(&[MessageType::ReplayListRequest.into()]),
+            Err(DecodeError::UnexpectedMessageType(MessageType::ReplayListRequest)));
+    }
+
+    #[test]
+    fn fighter_state_layout() {
+        let bytes = ServerMessage::FighterState(fighter_state()).to_bytes();
+        assert_eq!(bytes.len(), 30);
+        assert_eq!(bytes[0], MessageType::FighterState.into());
+        assert_eq!(&bytes[1..5], &25200u32.to_be_bytes());
+        assert_eq!(bytes[5], 1);
+        assert_eq!(&bytes[14..16], &6175u16.to_be_bytes());
+        assert_eq!(&bytes[22..27], &[0x12, 0x34, 0x56, 0x78, 0x9a]);
+        assert_eq!(bytes[29], 0b101);
+    }
+
+    #[test]
+    fn extended_fighter_state_only_has_selected_fields() {
+        let msg = ServerMessage::ExtendedFighterState { fields: fields::DAMAGE | fields::SHIELD, full_precision: false, state: fighter_state() };
+        let bytes = msg.to_bytes();
+        // type, frame, entry, fields, flags, damage, stocks, shield
+        assert_eq!(bytes.len(), 1 + 4 + 1 + 1 + 1 + 2 + 1 + 2);
+
+        let (decoded, _) = ServerMessage::decode(&bytes).unwrap();
+        let state = match decoded {
+            ServerMessage::ExtendedFighterState { fields, state, .. } => {
+                assert_eq!(fields, fields::DAMAGE | fields::SHIELD);
+                state
+            },
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(state.damage, 123.5);
+        assert_eq!(state.stock_count, 3);
+        assert_eq!(state.shield_size, 50.0);
+        assert_eq!(state.pos_x, 0.0);
+        assert_eq!(state.status_kind, 0);
+        assert!(state.attack_connected && state.opponent_in_hitlag);
+    }
+
+    #[test]
+    fn out_of_range_values_saturate() {
+        let mut state = fighter_state();
+        state.damage = 1500.0;
+        state.shield_size = -1.0;
+        let (decoded, _) = ServerMessage::decode(&ServerMessage::FighterState(state.clone()).to_bytes()).unwrap();
+        match decoded {
+            ServerMessage::FighterState(decoded) => {
+                assert_eq!(decoded.damage, u16::MAX as f32 / 50.0);
+                assert_eq!(decoded.shield_size, 0.0);
+                assert!(decoded.overflow);
+            },
+            other => panic!("{:?}", other),
+        }
+
+        // Only the groups that are sent count
+        let msg = ServerMessage::ExtendedFighterState { fields: fields::POSITION, full_precision: false, state: state.clone() };
+        let (decoded, _) = ServerMessage::decode(&msg.to_bytes()).unwrap();
+        assert!(matches!(decoded, ServerMessage::ExtendedFighterState { state, .. } if !state.overflow));
+
+        state.hitstun_left = 17.123;
+        state.hitlag_left = 12;
+        state.buttons = 0x0001_0041;
+        state.buttons_pressed = 0x40;
+        state.stick_x = -1.0;
+        state.c_stick_y = 1.0;
+        state.motion_frame = 6.5;
+        state.motion_end_frame = 32.0;
+        state.motion_rate = 0.75;
+        state.speed_x = 1.25;
+        state.damage_speed_x = 2.0;
+        state.control_speed_x = -0.75;
+        state.gravity_speed_y = -0.1;
+        let msg = ServerMessage::ExtendedFighterState { fields: fields::ALL, full_precision: true, state: state };
+        let (decoded, _) = ServerMessage::decode(&msg.to_bytes()).unwrap();
+        assert_eq!(decoded, msg);
+    }
+}
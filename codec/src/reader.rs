@@ -0,0 +1,86 @@
+use crate::DecodeError;
+
+/// Cursor over a byte slice that reads big endian values and reports
+/// DecodeError::Incomplete when running past the end.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::Incomplete);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// Reads a string prefixed with a u8 length
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u8()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+/// Writes a string prefixed with a u8 length. Strings longer than 255 bytes
+/// are truncated, without splitting a character in two.
+pub(crate) fn put_string(buf: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(255);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_strings_are_truncated_between_characters() {
+        // 254 bytes, then a 2 byte character that doesn't fit anymore
+        let s = "a".repeat(254) + "é";
+        let mut buf = Vec::new();
+        put_string(&mut buf, &s);
+        assert_eq!(buf[0], 254);
+        assert_eq!(Reader::new(&buf).string(), Ok("a".repeat(254)));
+        assert!(std::str::from_utf8(&buf[1..]).is_ok());
+    }
+}
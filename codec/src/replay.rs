@@ -0,0 +1,129 @@
+//! Replay files recorded on the console.
+//!
+//! Layout (all integers big endian):
+//!
+//! ```text
+//! magic             4 bytes "RFRP"
+//! format version    u8
+//! mapping checksum  u32  same value as MappingInfoChecksum, used to pick the
+//!                        right mapping info when decoding old files
+//! timestamp         u64  seconds since unix epoch, 0 if unknown
+//! ```
+//!
+//! followed by any number of records, each being a u16 length and a
+//! ServerMessage in the unframed encoding. The first record is always
+//! MatchStart and the last record is MatchEnd if the match finished
+//! normally.
+//!
+//! Format versions:
+//!
+//! 1. Fighters are recorded as FighterState
+//! 2. Fighters are recorded as ExtendedFighterState with all fields at full
+//!    precision, followed by HitboxState while they have any
+
+use crate::reader::Reader;
+use crate::{DecodeError, ServerMessage};
+
+pub const MAGIC: &[u8; 4] = b"RFRP";
+pub const FORMAT_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 17;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayHeader {
+    pub format_version: u8,
+    pub mapping_info_checksum: u32,
+    pub timestamp: u64,
+}
+
+impl ReplayHeader {
+    pub fn new(mapping_info_checksum: u32, timestamp: u64) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            mapping_info_checksum: mapping_info_checksum,
+            timestamp: timestamp,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(MAGIC);
+        buf.push(self.format_version);
+        buf.extend_from_slice(&self.mapping_info_checksum.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        if r.bytes(4)? != MAGIC {
+            return Err(DecodeError::Invalid("not a replay file"));
+        }
+        // Records decode the same way in every version, only what is
+        // recorded changed
+        let format_version = r.u8()?;
+        if !(1..=FORMAT_VERSION).contains(&format_version) {
+            return Err(DecodeError::Invalid("unsupported replay format version"));
+        }
+        Ok(Self {
+            format_version: format_version,
+            mapping_info_checksum: r.u32()?,
+            timestamp: r.u64()?,
+        })
+    }
+}
+
+/// Appends a length prefixed record containing an already encoded message
+pub fn encode_record(buf: &mut Vec<u8>, message: &[u8]) {
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+}
+
+/// Decodes the record at the start of the buffer and returns the message
+/// along with the number of bytes the record occupied
+pub fn decode_record(buf: &[u8]) -> Result<(ServerMessage, usize), DecodeError> {
+    let mut r = Reader::new(buf);
+    let len = r.u16()? as usize;
+    let record = r.bytes(len)?;
+    let (msg, used) = ServerMessage::decode(record)?;
+    if used != len {
+        return Err(DecodeError::Invalid("record length does not match message"));
+    }
+    Ok((msg, 2 + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TrainingStart;
+
+    #[test]
+    fn header_round_trip() {
+        let header = ReplayHeader::new(0xdeadbeef, 1700000000);
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        assert_eq!(buf.len(), HEADER_SIZE);
+        assert_eq!(ReplayHeader::decode(&buf), Ok(header));
+        assert_eq!(ReplayHeader::decode(b"RIFF1234567890123"), Err(DecodeError::Invalid("not a replay file")));
+
+        buf[4] = 1;
+        assert_eq!(ReplayHeader::decode(&buf).map(|h| h.format_version), Ok(1));
+        buf[4] = FORMAT_VERSION + 1;
+        assert_eq!(ReplayHeader::decode(&buf), Err(DecodeError::Invalid("unsupported replay format version")));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let msgs = [
+            ServerMessage::TrainingStart(TrainingStart { stage_id: 3, p1_fighter_kind: 8, cpu_fighter_kind: 1 }),
+            ServerMessage::MatchEnd,
+        ];
+        let mut buf = Vec::new();
+        for msg in msgs.iter() {
+            encode_record(&mut buf, &msg.to_bytes());
+        }
+
+        let (first, len) = decode_record(&buf).unwrap();
+        assert_eq!(first, msgs[0]);
+        let (second, len2) = decode_record(&buf[len..]).unwrap();
+        assert_eq!(second, msgs[1]);
+        assert_eq!(len + len2, buf.len());
+    }
+}
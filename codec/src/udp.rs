@@ -0,0 +1,50 @@
+//! Datagrams carrying frame states, see ClientMessage::UdpRequest.
+//!
+//! Every datagram holds exactly one framed message (see frame.rs) behind a
+//! sequence number:
+//!
+//! ```text
+//! sequence        u32 (big endian)
+//! message         framed FrameState, ExtendedFrameState, DeltaFrameState
+//!                 or HitboxState
+//! ```
+//!
+//! The sequence number starts at 0 and increases by one per datagram sent to
+//! the client. Datagrams can arrive out of order or not at all, clients
+//! should drop anything with a sequence number at or below the newest one
+//! they have seen instead of waiting for it.
+
+use crate::reader::Reader;
+use crate::{DecodeError, ServerMessage};
+
+pub const HEADER_SIZE: usize = 4;
+
+/// Appends the sequence number followed by an already framed message
+pub fn encode_datagram(buf: &mut Vec<u8>, sequence: u32, framed_message: &[u8]) {
+    buf.extend_from_slice(&sequence.to_be_bytes());
+    buf.extend_from_slice(framed_message);
+}
+
+/// Returns the sequence number and the message. Datagrams are never split,
+/// so DecodeError::Incomplete means the datagram was truncated.
+pub fn decode_datagram(buf: &[u8]) -> Result<(u32, ServerMessage), DecodeError> {
+    let sequence = Reader::new(buf).u32()?;
+    let (msg, _) = ServerMessage::decode_framed(&buf[HEADER_SIZE..])?;
+    Ok((sequence, msg?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FrameState;
+
+    #[test]
+    fn datagram_round_trip() {
+        let msg = ServerMessage::FrameState(FrameState { frame: 7, fighters: Vec::new() });
+        let mut buf = Vec::new();
+        encode_datagram(&mut buf, 0x01020304, &msg.to_framed_bytes());
+        assert_eq!(&buf[..HEADER_SIZE], &[1, 2, 3, 4]);
+        assert_eq!(decode_datagram(&buf), Ok((0x01020304, msg)));
+        assert_eq!(decode_datagram(&buf[..buf.len() - 1]), Err(DecodeError::Incomplete));
+    }
+}
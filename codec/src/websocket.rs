@@ -0,0 +1,237 @@
+//! Minimal server side of the WebSocket protocol (RFC 6455), for browser
+//! based clients such as stream overlays.
+//!
+//! Only what the plugin needs is implemented: the opening handshake, and
+//! unfragmented frames. Frames from the client are always masked, frames
+//! from the server never are.
+
+use crate::reader::Reader;
+use crate::DecodeError;
+
+pub const DEFAULT_PORT: u16 = 42071;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Nobody should be sending the server anything large, this only keeps a
+// broken client from making it allocate gigabytes
+const MAX_PAYLOAD_SIZE: u64 = 64 * 1024;
+
+// Browsers send well under 1 KB of headers with the upgrade request
+const MAX_UPGRADE_REQUEST_SIZE: usize = 8 * 1024;
+
+pub mod opcode {
+    pub const CONTINUATION: u8 = 0x0;
+    pub const TEXT: u8 = 0x1;
+    pub const BINARY: u8 = 0x2;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xa;
+}
+
+/// Value of the Sec-WebSocket-Accept header for the client's
+/// Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64(&sha1.digest().bytes())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | (b[2] as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Parses the HTTP upgrade request at the start of the buffer. Returns the
+/// client's Sec-WebSocket-Key and the length of the request.
+pub fn decode_upgrade_request(buf: &[u8]) -> Result<(String, usize), DecodeError> {
+    let len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() > MAX_UPGRADE_REQUEST_SIZE => return Err(DecodeError::Invalid("upgrade request too long")),
+        None => return Err(DecodeError::Incomplete),
+    };
+    if len > MAX_UPGRADE_REQUEST_SIZE {
+        return Err(DecodeError::Invalid("upgrade request too long"));
+    }
+
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut lines = request.split("\r\n");
+    if !lines.next().unwrap_or("").starts_with("GET ") {
+        return Err(DecodeError::Invalid("not a GET request"));
+    }
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                return Ok((value.trim().to_string(), len));
+            }
+        }
+    }
+    Err(DecodeError::Invalid("missing Sec-WebSocket-Key"))
+}
+
+/// Response accepting the upgrade request
+pub fn encode_upgrade_response(buf: &mut Vec<u8>, key: &str) {
+    buf.extend_from_slice(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    ).as_bytes());
+}
+
+/// Response for anything that isn't a valid upgrade request
+pub fn encode_bad_request(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    /// Already unmasked
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Decodes one frame sent by a client from the start of the buffer and
+    /// returns it along with the number of bytes it occupies
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let b0 = r.u8()?;
+        let b1 = r.u8()?;
+        if b1 & 0x80 == 0 {
+            return Err(DecodeError::Invalid("unmasked client frame"));
+        }
+        let len = match b1 & 0x7f {
+            126 => r.u16()? as u64,
+            127 => r.u64()?,
+            len => len as u64,
+        };
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(DecodeError::Invalid("frame too large"));
+        }
+        let mask = r.bytes(4)?;
+        let payload = r.bytes(len as usize)?.iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+
+        Ok((Self {
+            fin: b0 & 0x80 != 0,
+            opcode: b0 & 0x0f,
+            payload: payload,
+        }, r.position()))
+    }
+}
+
+/// Appends an unmasked, unfragmented frame
+pub fn encode_frame(buf: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    buf.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        buf.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(126);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(127);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    buf.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        // Example from RFC 6455 section 1.3
+        let request = b"GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        assert!(matches!(decode_upgrade_request(&request[..request.len() - 1]), Err(DecodeError::Incomplete)));
+        let (key, len) = decode_upgrade_request(request).unwrap();
+        assert_eq!(len, request.len());
+        assert_eq!(accept_key(&key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn upgrade_responses() {
+        // Browsers send a lot more than the RFC example, that still has to
+        // fit
+        let mut request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nCookie: ".to_vec();
+        request.resize(MAX_UPGRADE_REQUEST_SIZE - 4, b'a');
+        request.extend_from_slice(b"\r\n\r\n");
+        let (key, len) = decode_upgrade_request(&request).unwrap();
+        assert_eq!(len, MAX_UPGRADE_REQUEST_SIZE);
+
+        let mut response = Vec::new();
+        encode_upgrade_response(&mut response, &key);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert!(matches!(decode_upgrade_request(b"POST / HTTP/1.1\r\n\r\n"), Err(DecodeError::Invalid(_))));
+        assert!(matches!(decode_upgrade_request(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), Err(DecodeError::Invalid(_))));
+        let mut response = Vec::new();
+        encode_bad_request(&mut response);
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn oversized_upgrade_requests_are_rejected() {
+        let mut request = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        request.resize(MAX_UPGRADE_REQUEST_SIZE, b'a');
+        assert!(matches!(decode_upgrade_request(&request), Err(DecodeError::Incomplete)));
+        request.push(b'a');
+        assert!(matches!(decode_upgrade_request(&request), Err(DecodeError::Invalid(_))));
+        request.extend_from_slice(b"\r\n\r\n");
+        assert!(matches!(decode_upgrade_request(&request), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn client_frames_are_unmasked() {
+        // Masked "Hello" from RFC 6455 section 5.7
+        let buf = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        for len in 0..buf.len() {
+            assert!(matches!(Frame::decode(&buf[..len]), Err(DecodeError::Incomplete)));
+        }
+        let (frame, len) = Frame::decode(&buf).unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: opcode::TEXT, payload: b"Hello".to_vec() });
+        assert_eq!(len, buf.len());
+    }
+
+    #[test]
+    fn server_frame_lengths() {
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, opcode::TEXT, b"Hello");
+        assert_eq!(buf, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        buf.clear();
+        encode_frame(&mut buf, opcode::BINARY, &[0; 256]);
+        assert_eq!(&buf[..4], &[0x82, 126, 1, 0]);
+
+        buf.clear();
+        encode_frame(&mut buf, opcode::BINARY, &[0; 65536]);
+        assert_eq!(&buf[..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,78 @@
+use reframed_codec::Hitbox;
+
+/// Kinetic energies that make up a fighter's speed, see GameBackend::speed()
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Energy {
+    /// All of them added up
+    Sum,
+    Control,
+    Gravity,
+    Damage,
+    Motion,
+}
+
+/// Everything the server reads from the game. The plugin implements this on
+/// top of skyline and lua_bind (see skyline_backend.rs), and the simulator
+/// implements it with scripted matches so that the rest of the server can run
+/// on a PC.
+pub trait GameBackend {
+    /// Handle to a single fighter, passed to the per-fighter queries
+    type Fighter;
+
+    fn is_ready_go(&self) -> bool;
+    fn is_result_mode(&self) -> bool;
+    fn is_training_mode(&self) -> bool;
+    fn entry_count(&self) -> i32;
+    fn stage_id(&self) -> i32;
+    fn frames_left(&self) -> u32;
+
+    /// Name tag the player chose for the given slot, empty if none
+    fn player_tag(&self, entry_id: i32) -> String;
+
+    fn entry_id(&self, fighter: &Self::Fighter) -> i32;
+    fn fighter_kind(&self, fighter: &Self::Fighter) -> i32;
+    /// True for fighters that share their entry with the one they follow,
+    /// like Nana with Popo
+    fn is_follower(&self, fighter: &Self::Fighter) -> bool;
+    fn fighter_skin(&self, fighter: &Self::Fighter) -> i32;
+    fn stock_count(&self, fighter: &Self::Fighter) -> u8;
+    fn status_kind(&self, fighter: &Self::Fighter) -> i32;
+    fn motion_kind(&self, fighter: &Self::Fighter) -> u64;
+    fn damage(&self, fighter: &Self::Fighter) -> f32;
+    fn shield_size(&self, fighter: &Self::Fighter) -> f32;
+    fn attack_connected(&self, fighter: &Self::Fighter) -> bool;
+    fn hitstun_left(&self, fighter: &Self::Fighter) -> f32;
+    fn pos_x(&self, fighter: &Self::Fighter) -> f32;
+    fn pos_y(&self, fighter: &Self::Fighter) -> f32;
+    fn facing(&self, fighter: &Self::Fighter) -> f32;
+    fn hit_status(&self, fighter: &Self::Fighter) -> u64;
+    /// Frames the fighter is still frozen for. Called exactly once per
+    /// fighter per frame.
+    fn hitlag_left(&self, fighter: &Self::Fighter) -> u64;
+    fn opponent_in_hitlag(&self, fighter: &Self::Fighter) -> bool;
+
+    /// Held buttons, as CONTROL_PAD_BUTTON_* bits
+    fn buttons(&self, fighter: &Self::Fighter) -> u32;
+    /// Buttons that went down on this frame, same bits as buttons(). Not
+    /// the analog shoulder triggers.
+    fn buttons_pressed(&self, fighter: &Self::Fighter) -> u32;
+    /// Stick positions from -1.0 to 1.0
+    fn stick_x(&self, fighter: &Self::Fighter) -> f32;
+    fn stick_y(&self, fighter: &Self::Fighter) -> f32;
+    fn c_stick_x(&self, fighter: &Self::Fighter) -> f32;
+    fn c_stick_y(&self, fighter: &Self::Fighter) -> f32;
+
+    /// Current frame, last frame and playback rate of the animation of
+    /// motion_kind()
+    fn motion_frame(&self, fighter: &Self::Fighter) -> f32;
+    fn motion_end_frame(&self, fighter: &Self::Fighter) -> f32;
+    fn motion_rate(&self, fighter: &Self::Fighter) -> f32;
+
+    /// X and Y speed of one of the fighter's kinetic energies, in units per
+    /// frame
+    fn speed(&self, fighter: &Self::Fighter, energy: Energy) -> (f32, f32);
+
+    /// Hitboxes that are active on this frame, with the positions of their
+    /// bones filled in
+    fn hitboxes(&self, fighter: &Self::Fighter) -> Vec<Hitbox>;
+}
@@ -0,0 +1,6 @@
+use reframed_server::sim;
+
+fn main() {
+    sim::start_server();
+    sim::run(&sim::SimScript::demo());
+}
@@ -0,0 +1,269 @@
+use lazy_static::lazy_static;
+use log::{info, warn, LevelFilter};
+use reframed_codec::{discovery, websocket};
+use std::fs;
+use std::io;
+
+use crate::game_info;
+
+lazy_static!{
+    static ref CONFIG: Config = Config::load();
+}
+
+/*
+ * Settings read once at startup from an ini-style file:
+ *
+ *   # Comment
+ *   port = 42069
+ *   record_replays = false
+ *
+ * Unknown keys and values that fail to parse are reported in the log and
+ * the default is used instead. A missing file means all defaults.
+ *
+ * There is no setting for the listen backlog, std::net doesn't expose it.
+ * Use max_clients to limit the number of connections instead.
+ */
+#[cfg(feature = "skyline")]
+pub const CONFIG_PATH: &str = "sd:/ultimate/ReFramed/config.ini";
+#[cfg(not(feature = "skyline"))]
+pub const CONFIG_PATH: &str = "ReFramed/config.ini";
+
+pub struct Config {
+    port: u16,
+    max_clients: usize,
+    retry_delay_secs: u64,
+    log_level: LevelFilter,
+    stream_matches: bool,
+    stream_training: bool,
+    stream_frame_states: bool,
+    record_replays: bool,
+    max_replays: usize,
+    max_players: usize,
+    discovery: bool,
+    discovery_port: u16,
+    nickname: String,
+    websocket: bool,
+    websocket_port: u16,
+    heartbeat_secs: u64,
+    idle_timeout_secs: u64,
+    udp: bool,
+}
+
+impl Config {
+    pub fn get() -> &'static Self {
+        &CONFIG
+    }
+
+    pub fn new() -> Self {
+        Self {
+            port: 42069,
+            max_clients: 8,
+            retry_delay_secs: 10,
+            log_level: LevelFilter::Info,
+            stream_matches: true,
+            stream_training: true,
+            stream_frame_states: true,
+            record_replays: true,
+            max_replays: 0,
+            max_players: game_info::MAX_PLAYERS,
+            discovery: true,
+            discovery_port: discovery::DEFAULT_PORT,
+            nickname: "".to_string(),
+            websocket: true,
+            websocket_port: websocket::DEFAULT_PORT,
+            heartbeat_secs: 2,
+            idle_timeout_secs: 10,
+            udp: true,
+        }
+    }
+
+    /// TCP port of the server
+    pub fn port(&self) -> u16 { self.port }
+    /// Connections beyond this are closed right after accepting them
+    pub fn max_clients(&self) -> usize { self.max_clients }
+    /// How long to wait before restarting the server after it stopped
+    pub fn retry_delay_secs(&self) -> u64 { self.retry_delay_secs }
+    pub fn log_level(&self) -> LevelFilter { self.log_level }
+    /// Broadcast match start/end
+    pub fn stream_matches(&self) -> bool { self.stream_matches }
+    /// Broadcast training start/end
+    pub fn stream_training(&self) -> bool { self.stream_training }
+    /// Broadcast the state of each fighter every frame. Only done for
+    /// sessions whose start/end events are broadcast.
+    pub fn stream_frame_states(&self, training: bool) -> bool {
+        let session = if training { self.stream_training } else { self.stream_matches };
+        self.stream_frame_states && session
+    }
+    pub fn record_replays(&self) -> bool { self.record_replays }
+    /// Oldest replays are deleted once there are more than this, 0 keeps
+    /// all of them
+    pub fn max_replays(&self) -> usize { self.max_replays }
+    /// Matches with more players are ignored
+    pub fn max_players(&self) -> usize { self.max_players }
+    /// Answer LAN discovery probes, see reframed_codec::discovery
+    pub fn discovery(&self) -> bool { self.discovery }
+    pub fn discovery_port(&self) -> u16 { self.discovery_port }
+    /// Name reported to discovery probes instead of the console's nickname
+    pub fn nickname(&self) -> &str { &self.nickname }
+    /// Accept WebSocket connections from browser overlays, they are sent
+    /// events as JSON
+    pub fn websocket(&self) -> bool { self.websocket }
+    pub fn websocket_port(&self) -> u16 { self.websocket_port }
+    /// Clients that answer pings are pinged when nothing was received from
+    /// them for this long
+    pub fn heartbeat_secs(&self) -> u64 { self.heartbeat_secs }
+    /// Clients that answer pings are disconnected when nothing was received
+    /// from them for this long, 0 never disconnects them
+    pub fn idle_timeout_secs(&self) -> u64 { self.idle_timeout_secs }
+    /// Send frame states over UDP to clients that ask for it
+    pub fn udp(&self) -> bool { self.udp }
+
+    fn load() -> Self {
+        match fs::read_to_string(CONFIG_PATH) {
+            Ok(text) => {
+                info!("Loading config from {}", CONFIG_PATH);
+                Self::parse(&text)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No config file at {}, using defaults", CONFIG_PATH);
+                Self::new()
+            },
+            Err(e) => {
+                warn!("Failed to read {}: {}, using defaults", CONFIG_PATH, e);
+                Self::new()
+            }
+        }
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut config = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    warn!("{}:{}: expected 'key = value'", CONFIG_PATH, i + 1);
+                    continue;
+                }
+            };
+
+            if let Err(e) = config.set(key, value) {
+                warn!("{}:{}: {}", CONFIG_PATH, i + 1, e);
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "port" => self.port = parse_number(value)?,
+            "max_clients" => self.max_clients = parse_number(value)?,
+            "retry_delay_secs" => self.retry_delay_secs = parse_number(value)?,
+            "log_level" => self.log_level = value.parse()
+                .map_err(|_| format!("invalid log level '{}', expected off, error, warn, info, debug or trace", value))?,
+            "stream_matches" => self.stream_matches = parse_bool(value)?,
+            "stream_training" => self.stream_training = parse_bool(value)?,
+            "stream_frame_states" => self.stream_frame_states = parse_bool(value)?,
+            "record_replays" => self.record_replays = parse_bool(value)?,
+            "max_replays" => self.max_replays = parse_number(value)?,
+            "max_players" => {
+                let max_players: usize = parse_number(value)?;
+                if !(1..=game_info::MAX_PLAYERS).contains(&max_players) {
+                    return Err(format!("max_players must be between 1 and {}", game_info::MAX_PLAYERS));
+                }
+                self.max_players = max_players;
+            },
+            "discovery" => self.discovery = parse_bool(value)?,
+            "discovery_port" => self.discovery_port = parse_number(value)?,
+            "websocket" => self.websocket = parse_bool(value)?,
+            "websocket_port" => self.websocket_port = parse_number(value)?,
+            "heartbeat_secs" => {
+                let heartbeat_secs: u64 = parse_number(value)?;
+                if heartbeat_secs == 0 {
+                    return Err("heartbeat_secs must be at least 1".to_string());
+                }
+                self.heartbeat_secs = heartbeat_secs;
+            },
+            "idle_timeout_secs" => self.idle_timeout_secs = parse_number(value)?,
+            "udp" => self.udp = parse_bool(value)?,
+            "nickname" => self.nickname = value.to_string(),
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number '{}'", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("invalid boolean '{}', expected true or false", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_and_values() {
+        let config = Config::parse("\
+            # Comment\n\
+            ; Also a comment\n\
+            \n\
+            port = 1234\n\
+            \tmax_replays=5  \n\
+            record_replays = off\n\
+            log_level = debug\n\
+            nickname = Living room Switch\n");
+        assert_eq!(config.port(), 1234);
+        assert_eq!(config.max_replays(), 5);
+        assert!(!config.record_replays());
+        assert_eq!(config.log_level(), LevelFilter::Debug);
+        assert_eq!(config.nickname(), "Living room Switch");
+        assert_eq!(config.max_clients(), Config::new().max_clients());
+    }
+
+    #[test]
+    fn bad_lines_keep_the_default() {
+        let defaults = Config::new();
+        let config = Config::parse("\
+            port = 99999\n\
+            udp = maybe\n\
+            max_players = 9\n\
+            heartbeat_secs = 0\n\
+            websocket\n\
+            colour = blue\n\
+            max_clients = 2\n");
+        assert_eq!(config.port(), defaults.port());
+        assert_eq!(config.udp(), defaults.udp());
+        assert_eq!(config.max_players(), defaults.max_players());
+        assert_eq!(config.heartbeat_secs(), defaults.heartbeat_secs());
+        assert_eq!(config.websocket(), defaults.websocket());
+        // Lines after bad ones are still read
+        assert_eq!(config.max_clients(), 2);
+    }
+
+    #[test]
+    fn session_switches_suppress_frame_states() {
+        let config = Config::parse("stream_matches = off\n");
+        assert!(!config.stream_frame_states(false));
+        assert!(config.stream_frame_states(true));
+
+        let config = Config::parse("stream_training = off\n");
+        assert!(config.stream_frame_states(false));
+        assert!(!config.stream_frame_states(true));
+
+        let config = Config::parse("stream_frame_states = off\n");
+        assert!(!config.stream_frame_states(false));
+        assert!(!config.stream_frame_states(true));
+    }
+}
@@ -0,0 +1,78 @@
+use std::net::UdpSocket;
+use log::{debug, error, info, warn};
+use reframed_codec::discovery::{self, DiscoveryReply, SessionState};
+
+use crate::config::Config;
+use crate::game_info::GameInfo;
+use crate::protocol;
+use crate::training_info::TrainingInfo;
+
+/*
+ * Answers LAN discovery probes so that clients can list consoles instead of
+ * having to know their IP. See reframed_codec::discovery for the format.
+ */
+
+fn session_state() -> SessionState {
+    if GameInfo::get().lock().unwrap().match_is_running() {
+        SessionState::Match
+    } else if TrainingInfo::get().lock().unwrap().is_running() {
+        SessionState::Training
+    } else {
+        SessionState::Idle
+    }
+}
+
+fn nickname() -> String {
+    let nickname = Config::get().nickname();
+    if !nickname.is_empty() {
+        return nickname.to_string();
+    }
+
+    #[cfg(feature = "skyline")]
+    return crate::skyline_backend::device_nickname();
+    #[cfg(not(feature = "skyline"))]
+    return "ReFramed Simulator".to_string();
+}
+
+/// Only returns if the socket fails
+pub fn answer_probes() {
+    let port = Config::get().discovery_port();
+    let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind discovery socket: {}", e);
+            return;
+        }
+    };
+
+    info!("Answering discovery probes on UDP port {}", port);
+    let mut buf = [0u8; 64];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Failed to receive discovery probe: {}", e);
+                return;
+            }
+        };
+        if !discovery::is_probe(&buf[..len]) {
+            continue;
+        }
+
+        debug!("Discovery probe from {}", peer);
+        let (major, minor) = protocol::PROTOCOL_VERSION_MAX;
+        let reply = DiscoveryReply {
+            protocol_major: major,
+            protocol_minor: minor,
+            port: Config::get().port(),
+            mapping_info_checksum: protocol::mapping_info_checksum(),
+            session_state: session_state(),
+            nickname: nickname(),
+        };
+        let mut data = Vec::new();
+        reply.encode(&mut data);
+        if let Err(e) = socket.send_to(&data, peer) {
+            warn!("Failed to answer discovery probe from {}: {}", peer, e);
+        }
+    }
+}
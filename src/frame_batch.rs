@@ -0,0 +1,150 @@
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use reframed_codec::{FighterState, FrameState};
+
+lazy_static!{
+    static ref FRAME_BATCH: Mutex<FrameBatch> = Mutex::new(FrameBatch::new());
+}
+
+/*
+ * The game calls the per-fighter hook once for every fighter on every frame.
+ * Instead of sending each fighter on its own, the states are collected here
+ * and sent as one FrameState once every entry has reported. Frames are told
+ * apart by the frames_left counter. It doesn't run in training mode, where a
+ * frame is only sent because it is complete. Each entry is in a frame once,
+ * if an entry reports a second time (Nana shares Popo's entry) the first
+ * state is kept.
+ */
+pub struct FrameBatch {
+    frame: u32,
+    fighters: Vec<FighterState>,
+}
+
+impl FrameBatch {
+    pub fn get() -> &'static Mutex<Self> {
+        &FRAME_BATCH
+    }
+
+    pub fn new() -> Self {
+        Self {
+            frame: 0,
+            fighters: Vec::new(),
+        }
+    }
+
+    fn take(&mut self) -> Option<FrameState> {
+        if self.fighters.is_empty() {
+            return None;
+        }
+        let mut fighters = std::mem::take(&mut self.fighters);
+        fighters.sort_by_key(|f| f.entry_id);
+        Some(FrameState {
+            frame: self.frame,
+            fighters: fighters,
+        })
+    }
+
+    /// Returns the previous frame if it is still incomplete but `state`
+    /// belongs to a new one. That happens when a fighter is removed mid-match.
+    pub fn add(&mut self, state: FighterState) -> Option<FrameState> {
+        let is_new_frame = state.frame != self.frame;
+        if !is_new_frame && self.fighters.iter().any(|f| f.entry_id == state.entry_id) {
+            return None;
+        }
+        let previous = if is_new_frame { self.take() } else { None };
+
+        self.frame = state.frame;
+        self.fighters.push(state);
+        previous
+    }
+
+    /// Returns the current frame once all entries are in it
+    pub fn take_if_complete(&mut self, entry_count: usize) -> Option<FrameState> {
+        // add() keeps one state per entry, so this counts distinct entries
+        if self.fighters.len() >= entry_count {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Returns whatever was collected so far, for when a match ends
+    pub fn flush(&mut self) -> Option<FrameState> {
+        self.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(frame: u32, entry_id: u8) -> FighterState {
+        FighterState {
+            frame: frame,
+            entry_id: entry_id,
+            ..FighterState::default()
+        }
+    }
+
+    fn entries(frame: &FrameState) -> Vec<u8> {
+        frame.fighters.iter().map(|f| f.entry_id).collect()
+    }
+
+    #[test]
+    fn complete_frames_are_sorted_by_entry() {
+        let mut batch = FrameBatch::new();
+        assert!(batch.add(state(100, 2)).is_none());
+        assert!(batch.take_if_complete(3).is_none());
+        assert!(batch.add(state(100, 0)).is_none());
+        assert!(batch.add(state(100, 1)).is_none());
+
+        let frame = batch.take_if_complete(3).unwrap();
+        assert_eq!(frame.frame, 100);
+        assert_eq!(entries(&frame), vec![0, 1, 2]);
+        assert!(batch.flush().is_none());
+    }
+
+    #[test]
+    fn new_frame_returns_incomplete_one() {
+        let mut batch = FrameBatch::new();
+        batch.add(state(100, 0));
+        let previous = batch.add(state(99, 0)).unwrap();
+        assert_eq!(previous.frame, 100);
+        assert_eq!(entries(&previous), vec![0]);
+    }
+
+    #[test]
+    fn training_frames_are_sent_when_complete() {
+        // Training mode doesn't count frames
+        let mut batch = FrameBatch::new();
+        assert!(batch.add(state(0, 1)).is_none());
+        assert!(batch.add(state(0, 0)).is_none());
+        assert_eq!(entries(&batch.take_if_complete(2).unwrap()), vec![0, 1]);
+        assert!(batch.add(state(0, 0)).is_none());
+        assert!(batch.take_if_complete(2).is_none());
+        assert!(batch.add(state(0, 1)).is_none());
+        assert_eq!(entries(&batch.take_if_complete(2).unwrap()), vec![0, 1]);
+    }
+
+    #[test]
+    fn ice_climbers_share_an_entry() {
+        let popo = FighterState {
+            pos_x: -20.0,
+            ..state(100, 0)
+        };
+        let nana = FighterState {
+            pos_x: -25.0,
+            ..state(100, 0)
+        };
+
+        let mut batch = FrameBatch::new();
+        assert!(batch.add(popo).is_none());
+        assert!(batch.add(nana).is_none());
+        assert!(batch.take_if_complete(2).is_none());
+        assert!(batch.add(state(100, 1)).is_none());
+
+        let frame = batch.take_if_complete(2).unwrap();
+        assert_eq!(entries(&frame), vec![0, 1]);
+        assert_eq!(frame.fighters[0].pos_x, -20.0);
+    }
+}
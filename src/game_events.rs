@@ -0,0 +1,189 @@
+use reframed_codec::FighterState;
+
+use crate::backend::{Energy, GameBackend};
+use crate::config::Config;
+use crate::game_info::GameInfo;
+use crate::hitboxes::HitboxTracker;
+use crate::hitlag::HitlagTracker;
+use crate::training_info::TrainingInfo;
+use crate::protocol;
+use crate::server::Server;
+
+/*
+ * Start/end detection of matches and training sessions. This is driven by the
+ * global_reset() and per-frame hooks on the console, and by the simulator on a
+ * PC. See lib.rs for the order in which the game calls the hooks.
+ */
+
+pub fn global_reset<B: GameBackend>(game: &B, server: &Server) {
+    let is_ready_go = game.is_ready_go();
+    let is_result_mode = game.is_result_mode();
+    let is_training_mode = game.is_training_mode();
+
+    if is_training_mode {
+        let mut training_info = TrainingInfo::get().lock().unwrap();
+        if !training_info.is_running() {
+            training_info.set_start_pending();
+        }
+    }
+
+    if !is_training_mode && !is_ready_go && is_result_mode {
+        let mut game_info = GameInfo::get().lock().unwrap();
+        if game_info.match_is_running() {
+            game_info.set_match_end();
+            HitboxTracker::get().lock().unwrap().reset();
+            protocol::broadcast_match_end(server);
+        }
+    }
+}
+
+pub fn fighter_frame<B: GameBackend>(game: &B, fighter: &B::Fighter, server: &Server) {
+    let fighter_entry_id = game.entry_id(fighter);
+    let fighter_kind = game.fighter_kind(fighter);
+    let is_ready_go = game.is_ready_go();
+    let is_training_mode = game.is_training_mode();
+    let num_fighters = game.entry_count();
+    // Nana reports with Popo's entry ID. Only her hitboxes are sent, the
+    // entry is described by Popo.
+    let is_follower = game.is_follower(fighter);
+
+    if is_training_mode {
+        let mut training_info = TrainingInfo::get().lock().unwrap();
+
+        // Start notification logic. Have to collect info over multiple
+        // callbacks to this function before being able to send
+        // the start event, but the actual detection of the start
+        // event happens in the global_reset() hook.
+        if is_ready_go && !is_follower && training_info.is_start_pending() {
+            if fighter_entry_id == 0 {
+                training_info.set_player_info(fighter_kind);
+            }
+            if fighter_entry_id == 1 {
+                training_info.set_cpu_info(fighter_kind);
+            }
+            training_info.set_stage(game.stage_id());
+
+            if training_info.have_enough_info_to_start() {
+                training_info.start();
+                HitlagTracker::get().lock().unwrap().reset();
+                HitboxTracker::get().lock().unwrap().reset();
+                protocol::broadcast_training_start(server, &training_info);
+            }
+        }
+
+        // Stop notification logic
+        if !is_ready_go && training_info.is_running() {
+            training_info.stop();
+            HitboxTracker::get().lock().unwrap().reset();
+            protocol::broadcast_training_end(server);
+        }
+
+        // Don't send player states if training mode hasn't started
+        if !training_info.is_running() {
+            return;
+        }
+    } else {
+        if num_fighters < 1 || num_fighters > Config::get().max_players() as i32 {
+            return;
+        }
+
+        let mut game_info = GameInfo::get().lock().unwrap();
+
+        // Start notification logic. Have to collect info over multiple
+        // callbacks to this function before being able to send the
+        // start event.
+        if is_ready_go && !is_follower && !game_info.match_is_running() {
+            let player_tag = game.player_tag(fighter_entry_id);
+            let player_tag = if player_tag.is_empty() {
+                format!("Player {}", fighter_entry_id + 1)
+            } else {
+                player_tag
+            };
+
+            game_info.set_player_info(
+                    fighter_entry_id,
+                    &player_tag,
+                    fighter_kind,
+                    game.fighter_skin(fighter));
+            game_info.set_player_count(num_fighters);
+            game_info.set_stage(game.stage_id());
+
+            if game_info.have_enough_info_to_start_match() {
+                game_info.set_match_start();
+                HitlagTracker::get().lock().unwrap().reset();
+                HitboxTracker::get().lock().unwrap().reset();
+                protocol::broadcast_match_start(server, &game_info);
+            }
+        }
+
+        // Don't send player states if match hasn't started
+        if !game_info.match_is_running() {
+            return;
+        }
+    }
+
+    // TODO
+    // Figure out when BoX sets start and end
+    // Iframes
+
+    // Hitboxes go out first, the frame state is sent as soon as the last
+    // fighter of the frame is added
+    protocol::broadcast_hitboxes(server,
+        is_training_mode,
+        game.frames_left(),
+        fighter_entry_id,
+        game.hitboxes(fighter),
+    );
+
+    if is_follower {
+        return;
+    }
+    protocol::broadcast_fighter_info(server, is_training_mode, num_fighters, fighter_state(game, fighter));
+}
+
+fn fighter_state<B: GameBackend>(game: &B, fighter: &B::Fighter) -> FighterState {
+    let (speed_x, speed_y) = game.speed(fighter, Energy::Sum);
+    let (control_speed_x, control_speed_y) = game.speed(fighter, Energy::Control);
+    let (gravity_speed_x, gravity_speed_y) = game.speed(fighter, Energy::Gravity);
+    let (damage_speed_x, damage_speed_y) = game.speed(fighter, Energy::Damage);
+    let (motion_speed_x, motion_speed_y) = game.speed(fighter, Energy::Motion);
+
+    FighterState {
+        frame: game.frames_left(),
+        entry_id: game.entry_id(fighter) as u8,
+        pos_x: game.pos_x(fighter),
+        pos_y: game.pos_y(fighter),
+        damage: game.damage(fighter),
+        hitstun_left: game.hitstun_left(fighter),
+        hitlag_left: game.hitlag_left(fighter).min(u8::MAX as u64) as u8,
+        shield_size: game.shield_size(fighter),
+        // Highest status value seems to be 872 (I'm looking at you kirby)
+        status_kind: game.status_kind(fighter) as u16,
+        motion_kind: game.motion_kind(fighter),
+        hit_status: game.hit_status(fighter) as u8,
+        stock_count: game.stock_count(fighter),
+        attack_connected: game.attack_connected(fighter),
+        facing_right: game.facing(fighter) > 0.0,
+        opponent_in_hitlag: game.opponent_in_hitlag(fighter),
+        overflow: false,
+        buttons: game.buttons(fighter),
+        buttons_pressed: game.buttons_pressed(fighter),
+        stick_x: game.stick_x(fighter),
+        stick_y: game.stick_y(fighter),
+        c_stick_x: game.c_stick_x(fighter),
+        c_stick_y: game.c_stick_y(fighter),
+        motion_frame: game.motion_frame(fighter),
+        motion_end_frame: game.motion_end_frame(fighter),
+        motion_rate: game.motion_rate(fighter),
+        speed_x: speed_x,
+        speed_y: speed_y,
+        control_speed_x: control_speed_x,
+        control_speed_y: control_speed_y,
+        gravity_speed_x: gravity_speed_x,
+        gravity_speed_y: gravity_speed_y,
+        damage_speed_x: damage_speed_x,
+        damage_speed_y: damage_speed_y,
+        motion_speed_x: motion_speed_x,
+        motion_speed_y: motion_speed_y,
+    }
+}
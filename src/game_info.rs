@@ -5,20 +5,32 @@ lazy_static!{
     static ref GAME_INFO: Mutex<GameInfo> = Mutex::new(GameInfo::new());
 }
 
+// Smash supports up to 8 players in a single match
+pub const MAX_PLAYERS: usize = 8;
+
+pub struct PlayerInfo {
+    entry_id: i32,
+    name: String,
+    fighter_kind: i32,
+    fighter_skin: i32
+}
+
+impl PlayerInfo {
+    pub fn entry_id(&self) -> i32 { self.entry_id }
+    pub fn name(&self) -> &String { &self.name }
+    pub fn fighter_kind(&self) -> i32 { self.fighter_kind }
+    pub fn fighter_skin(&self) -> i32 { self.fighter_skin }
+}
+
 pub struct GameInfo {
     match_is_running: bool,
 
     stage_id: i32,
 
-    p1_entry_id: i32,
-    p1_name: String,
-    p1_fighter_kind: i32,
-    p1_fighter_skin: i32,
-
-    p2_entry_id: i32,
-    p2_name: String,
-    p2_fighter_kind: i32,
-    p2_fighter_skin: i32
+    // Number of entries the fighter manager reported for this match. The
+    // match start is only sent once we've seen every one of them.
+    player_count: i32,
+    players: Vec<PlayerInfo>
 }
 
 impl GameInfo {
@@ -30,21 +42,15 @@ impl GameInfo {
         Self {
             match_is_running: false,
             stage_id: -1,
-            p1_entry_id: -1,
-            p1_name: "".to_string(),
-            p1_fighter_kind: -1,
-            p1_fighter_skin: -1,
-            p2_entry_id: -1,
-            p2_name: "".to_string(),
-            p2_fighter_kind: -1,
-            p2_fighter_skin: -1
+            player_count: -1,
+            players: Vec::new()
         }
     }
 
     pub fn set_match_end(&mut self) {
         self.match_is_running = false;
-        self.p1_entry_id = -1;
-        self.p2_entry_id = -1;
+        self.player_count = -1;
+        self.players.clear();
     }
 
     pub fn set_match_start(&mut self) {
@@ -52,25 +58,33 @@ impl GameInfo {
     }
 
     pub fn have_enough_info_to_start_match(&self) -> bool {
-        self.p1_entry_id != -1 && self.p2_entry_id != -1 && self.stage_id != -1
+        self.player_count > 0
+            && self.players.len() == self.player_count as usize
+            && self.stage_id != -1
     }
 
     pub fn match_is_running(&self) -> bool {
         self.match_is_running
     }
 
+    pub fn set_player_count(&mut self, count: i32) {
+        self.player_count = count;
+    }
+
     pub fn set_player_info(&mut self, entry_id: i32, name: &str, fighter_kind: i32, fighter_skin: i32) {
-        if self.p1_entry_id == -1 {
-            self.p1_entry_id = entry_id;
-            self.p1_name = name.to_string();
-            self.p1_fighter_kind = fighter_kind;
-            self.p1_fighter_skin = fighter_skin;
-        } else {            
-            self.p2_entry_id = entry_id;
-            self.p2_name = name.to_string();
-            self.p2_fighter_kind = fighter_kind;
-            self.p2_fighter_skin = fighter_skin;
+        // Every fighter calls this once per frame until the match starts,
+        // only the first call for each entry matters
+        if self.players.len() >= MAX_PLAYERS || self.players.iter().any(|p| p.entry_id == entry_id) {
+            return;
         }
+
+        self.players.push(PlayerInfo {
+            entry_id: entry_id,
+            name: name.to_string(),
+            fighter_kind: fighter_kind,
+            fighter_skin: fighter_skin
+        });
+        self.players.sort_by_key(|p| p.entry_id);
     }
 
     pub fn set_stage(&mut self, stage_id: i32) {
@@ -79,14 +93,5 @@ impl GameInfo {
 
     pub fn get_stage(&self) -> i32 { self.stage_id }
 
-    pub fn p1_entry_id(&self) -> i32 { self.p1_entry_id }
-    pub fn p1_name(&self) -> &String { &self.p1_name }
-    pub fn p1_fighter_kind(&self) -> i32 { self.p1_fighter_kind }
-    pub fn p1_fighter_skin(&self) -> i32 { self.p1_fighter_skin }
-
-    pub fn p2_entry_id(&self) -> i32 { self.p2_entry_id }
-    pub fn p2_name(&self) -> &String { &self.p2_name }
-    pub fn p2_fighter_kind(&self) -> i32 { self.p2_fighter_kind }
-    pub fn p2_fighter_skin(&self) -> i32 { self.p2_fighter_skin }
+    pub fn players(&self) -> &[PlayerInfo] { &self.players }
 }
-
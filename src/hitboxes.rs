@@ -0,0 +1,93 @@
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use reframed_codec::Hitbox;
+
+lazy_static!{
+    static ref HITBOX_TRACKER: Mutex<HitboxTracker> = Mutex::new(HitboxTracker::new());
+}
+
+/*
+ * AttackModule can tell whether a hitbox is active, but not what it looks
+ * like. The plugin hooks the ATTACK function of the move scripts (see lib.rs)
+ * and keeps the last definition of every hitbox ID here. On every frame the
+ * backend asks AttackModule which of them are still active and where their
+ * bones are.
+ *
+ * Hitboxes belong to a battle object ID, like the hitlag in hitlag.rs. Popo
+ * and Nana share an entry ID but attack separately.
+ */
+pub struct HitboxTracker {
+    // Battle object ID and hitbox
+    hitboxes: Vec<(u32, Hitbox)>,
+}
+
+impl HitboxTracker {
+    pub fn get() -> &'static Mutex<Self> {
+        &HITBOX_TRACKER
+    }
+
+    pub fn new() -> Self {
+        Self {
+            hitboxes: Vec::new(),
+        }
+    }
+
+    /// Called when a move's script creates a hitbox. Replaces the previous
+    /// hitbox with the same ID.
+    pub fn define(&mut self, object_id: u32, hitbox: Hitbox) {
+        self.hitboxes.retain(|(id, h)| *id != object_id || h.id != hitbox.id);
+        self.hitboxes.push((object_id, hitbox));
+    }
+
+    /// Every hitbox the fighter had since the start of the match, active or
+    /// not
+    pub fn hitboxes(&self, object_id: u32) -> Vec<Hitbox> {
+        self.hitboxes.iter()
+            .filter(|(id, _)| *id == object_id)
+            .map(|(_, h)| h.clone())
+            .collect()
+    }
+
+    /// Battle object IDs are reused by the next match
+    pub fn reset(&mut self) {
+        self.hitboxes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hitbox(id: u8, damage: f32) -> Hitbox {
+        Hitbox {
+            id: id,
+            damage: damage,
+            ..Hitbox::default()
+        }
+    }
+
+    #[test]
+    fn redefining_replaces_the_hitbox() {
+        let mut tracker = HitboxTracker::new();
+        tracker.define(7, hitbox(0, 5.0));
+        tracker.define(7, hitbox(1, 3.0));
+        tracker.define(7, hitbox(0, 8.0));
+
+        let hitboxes = tracker.hitboxes(7);
+        assert_eq!(hitboxes.len(), 2);
+        assert_eq!(hitboxes.iter().find(|h| h.id == 0).unwrap().damage, 8.0);
+    }
+
+    #[test]
+    fn objects_sharing_an_entry_are_separate() {
+        // Popo and Nana
+        let mut tracker = HitboxTracker::new();
+        tracker.define(7, hitbox(0, 5.0));
+        tracker.define(8, hitbox(0, 4.0));
+        assert_eq!(tracker.hitboxes(7)[0].damage, 5.0);
+        assert_eq!(tracker.hitboxes(8)[0].damage, 4.0);
+
+        tracker.reset();
+        assert!(tracker.hitboxes(7).is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+lazy_static!{
+    static ref HITLAG_TRACKER: Mutex<HitlagTracker> = Mutex::new(HitlagTracker::new());
+}
+
+/*
+ * StopModule::get_hit_stop_real_frame() only works for "normal" attacks. For
+ * electric ones (pika dair, nair, thunder jolt, ...) and some multi-hits it
+ * reports 0 even though the fighter is frozen. What does work is the moment
+ * the game puts a fighter into hitlag, so the plugin hooks the StopModule
+ * setters (see lib.rs) and the frames are counted down here, once per frame
+ * from the per-fighter hook. A new hit while still in hitlag starts over
+ * with the new number of frames, same as the game does.
+ *
+ * Fighters are told apart by their battle object ID. Ice Climbers share one
+ * entry ID, but Popo and Nana can be in hitlag separately and the hook runs
+ * for both of them.
+ */
+pub struct HitlagTracker {
+    // Battle object ID and hitlag frames left
+    frames_left: Vec<(u32, u32)>,
+}
+
+impl HitlagTracker {
+    pub fn get() -> &'static Mutex<Self> {
+        &HITLAG_TRACKER
+    }
+
+    pub fn new() -> Self {
+        Self {
+            frames_left: Vec::new(),
+        }
+    }
+
+    /// Called when the game puts a fighter into hitlag
+    pub fn set_hitlag(&mut self, object_id: u32, frames: u32) {
+        match self.frames_left.iter_mut().find(|(id, _)| *id == object_id) {
+            Some((_, frames_left)) => *frames_left = frames,
+            None => self.frames_left.push((object_id, frames)),
+        }
+    }
+
+    /// Returns the hitlag frames left on the current frame and counts one
+    /// down. Has to be called exactly once per fighter per frame.
+    pub fn next_frame(&mut self, object_id: u32) -> u32 {
+        match self.frames_left.iter_mut().find(|(id, _)| *id == object_id) {
+            Some((_, frames_left)) => {
+                let current = *frames_left;
+                *frames_left = frames_left.saturating_sub(1);
+                current
+            },
+            None => 0,
+        }
+    }
+
+    /// Nobody is in hitlag at the start of a match
+    pub fn reset(&mut self) {
+        self.frames_left.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_down_once_per_frame() {
+        let mut tracker = HitlagTracker::new();
+        assert_eq!(tracker.next_frame(7), 0);
+        tracker.set_hitlag(7, 3);
+        let frames: Vec<u32> = (0..5).map(|_| tracker.next_frame(7)).collect();
+        assert_eq!(frames, vec![3, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn new_hit_starts_over() {
+        let mut tracker = HitlagTracker::new();
+        tracker.set_hitlag(7, 3);
+        tracker.next_frame(7);
+        tracker.set_hitlag(7, 5);
+        assert_eq!(tracker.next_frame(7), 5);
+        tracker.reset();
+        assert_eq!(tracker.next_frame(7), 0);
+    }
+
+    #[test]
+    fn objects_count_down_separately() {
+        // Popo and Nana, with the same entry ID but different objects
+        let mut tracker = HitlagTracker::new();
+        tracker.set_hitlag(1, 4);
+        tracker.set_hitlag(2, 4);
+        assert_eq!(tracker.next_frame(1), 4);
+        assert_eq!(tracker.next_frame(2), 4);
+        assert_eq!(tracker.next_frame(1), 3);
+        assert_eq!(tracker.next_frame(2), 3);
+    }
+}
@@ -0,0 +1,409 @@
+use serde_json::{json, Value};
+use reframed_codec::{ClientMessage, DecodeError, FighterState, FrameState, MatchStart, ServerMessage, Subscription, TrainingStart};
+
+use crate::mapping_info::MappingInfo;
+
+/*
+ * JSON representation of the protocol, for clients that can't or don't want
+ * to decode the binary format (see server::Encoding). Every message is an
+ * object with a "type" member named after its MessageType. Fighter, stage and
+ * status kinds are sent as numbers along with their names from the mapping
+ * info, if there is one.
+ */
+
+/// Switches a binary connection to JSON lines. Starts with a byte that isn't
+/// a message type, so it can't be mistaken for a binary message. Has to be
+/// sent before negotiating a framed version.
+pub const JSON_LINES_REQUEST: &str = "json";
+
+/// Client messages in JSON are a few dozen bytes. A client that sends more
+/// than this without a newline is broken and gets disconnected.
+pub const MAX_LINE_LENGTH: usize = 4 * 1024;
+
+fn match_start(info: &MatchStart) -> Value {
+    let mapping_info = MappingInfo::get();
+    json!({
+        "stage_id": info.stage_id,
+        "stage_name": mapping_info.stage_kind_name(info.stage_id),
+        "players": info.players.iter().map(|p| json!({
+            "entry_id": p.entry_id,
+            "fighter_kind": p.fighter_kind,
+            "fighter_kind_name": mapping_info.fighter_kind_name(p.fighter_kind),
+            "fighter_skin": p.fighter_skin,
+            "name": p.name,
+        })).collect::<Vec<Value>>(),
+    })
+}
+
+fn training_start(info: &TrainingStart) -> Value {
+    let mapping_info = MappingInfo::get();
+    json!({
+        "stage_id": info.stage_id,
+        "stage_name": mapping_info.stage_kind_name(info.stage_id),
+        "p1_fighter_kind": info.p1_fighter_kind,
+        "p1_fighter_kind_name": mapping_info.fighter_kind_name(info.p1_fighter_kind),
+        "cpu_fighter_kind": info.cpu_fighter_kind,
+        "cpu_fighter_kind_name": mapping_info.fighter_kind_name(info.cpu_fighter_kind),
+    })
+}
+
+// Status kinds are only unique per fighter, `fighter_kinds` maps the entry
+// IDs of the current session to fighter kinds
+fn fighter_state(state: &FighterState, fighter_kinds: &[(u8, u8)]) -> Value {
+    let mapping_info = MappingInfo::get();
+    let fighter_kind = fighter_kinds.iter()
+        .find(|(entry_id, _)| *entry_id == state.entry_id)
+        .map(|(_, fighter_kind)| *fighter_kind);
+    json!({
+        "frame": state.frame,
+        "entry_id": state.entry_id,
+        "pos_x": state.pos_x,
+        "pos_y": state.pos_y,
+        "speed_x": state.speed_x,
+        "speed_y": state.speed_y,
+        "control_speed_x": state.control_speed_x,
+        "control_speed_y": state.control_speed_y,
+        "gravity_speed_x": state.gravity_speed_x,
+        "gravity_speed_y": state.gravity_speed_y,
+        "damage_speed_x": state.damage_speed_x,
+        "damage_speed_y": state.damage_speed_y,
+        "motion_speed_x": state.motion_speed_x,
+        "motion_speed_y": state.motion_speed_y,
+        "damage": state.damage,
+        "hitstun_left": state.hitstun_left,
+        "hitlag_left": state.hitlag_left,
+        "shield_size": state.shield_size,
+        "status_kind": state.status_kind,
+        "status_kind_name": mapping_info.fighter_status_kind_name(fighter_kind, state.status_kind),
+        "motion_kind": format!("0x{:010x}", state.motion_kind),
+        "motion_frame": state.motion_frame,
+        "motion_end_frame": state.motion_end_frame,
+        "motion_rate": state.motion_rate,
+        "hit_status": state.hit_status,
+        "hit_status_name": mapping_info.hit_status_kind_name(state.hit_status),
+        "stock_count": state.stock_count,
+        "attack_connected": state.attack_connected,
+        "facing_right": state.facing_right,
+        "opponent_in_hitlag": state.opponent_in_hitlag,
+        "overflow": state.overflow,
+        "buttons": state.buttons,
+        "buttons_pressed": state.buttons_pressed,
+        "stick_x": state.stick_x,
+        "stick_y": state.stick_y,
+        "c_stick_x": state.c_stick_x,
+        "c_stick_y": state.c_stick_y,
+    })
+}
+
+fn frame_state(state: &FrameState, fighter_kinds: &[(u8, u8)]) -> Value {
+    json!({
+        "frame": state.frame,
+        "fighters": state.fighters.iter()
+            .map(|f| fighter_state(f, fighter_kinds))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// See fighter_state() for `fighter_kinds`
+pub fn message(msg: &ServerMessage, fighter_kinds: &[(u8, u8)]) -> Value {
+    let mut value = match msg {
+        ServerMessage::ProtocolVersion { major, minor } => json!({ "major": major, "minor": minor }),
+        ServerMessage::HandshakeAccept { major, minor, capabilities } =>
+            json!({ "major": major, "minor": minor, "capabilities": capabilities }),
+        ServerMessage::HandshakeReject { reason } => json!({ "reason": reason }),
+        ServerMessage::MappingInfoChecksum(checksum) |
+        ServerMessage::MappingInfoRequest(checksum) => json!({ "checksum": checksum }),
+        ServerMessage::MappingInfoFighterKind { fighter_kind, name } =>
+            json!({ "fighter_kind": fighter_kind, "name": name }),
+        ServerMessage::MappingInfoFighterStatusKind { fighter_kind, status_kind, name } =>
+            json!({ "fighter_kind": fighter_kind, "status_kind": status_kind, "name": name }),
+        ServerMessage::MappingInfoStageKind { stage_kind, name } =>
+            json!({ "stage_kind": stage_kind, "name": name }),
+        ServerMessage::MappingInfoHitStatusKind { hit_status_kind, name } =>
+            json!({ "hit_status_kind": hit_status_kind, "name": name }),
+        ServerMessage::MatchStart(info) |
+        ServerMessage::MatchResume(info) => match_start(info),
+        ServerMessage::TrainingStart(info) |
+        ServerMessage::TrainingResume(info) => training_start(info),
+        ServerMessage::MappingInfoRequestComplete |
+        ServerMessage::MatchEnd |
+        ServerMessage::TrainingReset |
+        ServerMessage::TrainingEnd |
+        ServerMessage::ReplayListComplete => json!({}),
+        ServerMessage::FighterState(state) |
+        ServerMessage::ExtendedFighterState { state, .. } => fighter_state(state, fighter_kinds),
+        ServerMessage::FrameState(state) |
+        ServerMessage::ExtendedFrameState { state, .. } => frame_state(state, fighter_kinds),
+        ServerMessage::DeltaFrameState(delta) => json!({
+            "sequence": delta.sequence,
+            "keyframe": delta.keyframe,
+            "frame": delta.frame,
+            "fighters": delta.fighters.iter().map(|(fields, f)| {
+                let mut value = fighter_state(f, fighter_kinds);
+                value["fields"] = json!(fields);
+                value
+            }).collect::<Vec<Value>>(),
+        }),
+        ServerMessage::HitboxState { frame, entry_id, hitboxes } => json!({
+            "frame": frame,
+            "entry_id": entry_id,
+            "hitboxes": hitboxes.iter().map(|h| json!({
+                "id": h.id,
+                "kind": format!("0x{:010x}", h.kind),
+                "bone": format!("0x{:010x}", h.bone),
+                "bone_x": h.bone_x,
+                "bone_y": h.bone_y,
+                "offset_x": h.offset_x,
+                "offset_y": h.offset_y,
+                "offset_z": h.offset_z,
+                "size": h.size,
+                "damage": h.damage,
+                "angle": h.angle,
+                "knockback_growth": h.knockback_growth,
+                "fixed_knockback": h.fixed_knockback,
+                "base_knockback": h.base_knockback,
+            })).collect::<Vec<Value>>(),
+        }),
+        ServerMessage::ClientList(clients) => json!({
+            "clients": clients.iter().map(|c| json!({
+                "id": c.id,
+                "address": c.address,
+                "connected_at": c.connected_at,
+                "capabilities": c.capabilities,
+                "framed": c.framed,
+                "subscribed": c.subscribed,
+            })).collect::<Vec<Value>>(),
+        }),
+        ServerMessage::ReplayListEntry(entry) => json!({
+            "name": entry.name,
+            "size": entry.size,
+            "timestamp": entry.timestamp,
+            "mapping_info_checksum": entry.mapping_info_checksum,
+            "match_start": match_start(&entry.match_start),
+        }),
+        ServerMessage::ReplayDownloadChunk { offset, data } => json!({ "offset": offset, "data": hex(data) }),
+        ServerMessage::ReplayDownloadComplete { size } => json!({ "size": size }),
+        ServerMessage::Ping { timestamp } |
+        ServerMessage::Pong { timestamp } => json!({ "timestamp": timestamp }),
+        ServerMessage::UdpResponse { port } => json!({ "port": port }),
+    };
+
+    // The extended variants only differ in how they are packed
+    let msg_type = match msg {
+        ServerMessage::ExtendedFighterState { .. } => "FighterState".to_string(),
+        ServerMessage::ExtendedFrameState { .. } => "FrameState".to_string(),
+        _ => format!("{:?}", msg.message_type()),
+    };
+    value["type"] = Value::String(msg_type);
+    value
+}
+
+fn get_u8(value: &Value, key: &str, default: u8) -> Result<u8, String> {
+    match value.get(key) {
+        None => Ok(default),
+        Some(v) => v.as_u64()
+            .and_then(|v| u8::try_from(v).ok())
+            .ok_or(format!("\"{}\" has to be a number from 0 to 255", key)),
+    }
+}
+
+fn get_u64(value: &Value, key: &str) -> Result<u64, String> {
+    match value.get(key) {
+        None => Ok(0),
+        Some(v) => v.as_u64().ok_or(format!("\"{}\" has to be a positive number", key)),
+    }
+}
+
+pub fn is_json_lines_request(line: &str) -> bool {
+    line.trim() == JSON_LINES_REQUEST
+}
+
+/// Takes the next line, without its newline, off the start of `buf`
+pub fn take_line(buf: &mut Vec<u8>) -> Result<String, DecodeError> {
+    let len = match buf.iter().position(|b| *b == b'\n') {
+        Some(pos) if pos < MAX_LINE_LENGTH => pos,
+        None if buf.len() <= MAX_LINE_LENGTH => return Err(DecodeError::Incomplete),
+        _ => return Err(DecodeError::Invalid("line too long")),
+    };
+    let line: Vec<u8> = buf.drain(..len + 1).collect();
+    Ok(String::from_utf8_lossy(&line[..len]).into_owned())
+}
+
+/// Parses one line sent by a client. A line is either a JSON object with a
+/// "type" member and the message's fields, or just the type for messages
+/// without fields. Returns None for empty lines.
+pub fn client_message(line: &str) -> Result<Option<ClientMessage>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let value = if line.starts_with('{') {
+        serde_json::from_str(line).map_err(|e| e.to_string())?
+    } else {
+        json!({ "type": line })
+    };
+
+    let msg_type = value.get("type")
+        .and_then(|t| t.as_str())
+        .ok_or("missing \"type\"")?;
+    let all = Subscription::all();
+    let msg = match msg_type {
+        "ProtocolVersion" => ClientMessage::ProtocolVersion,
+        "ClientProtocolVersion" => ClientMessage::ClientProtocolVersion {
+            major: get_u8(&value, "major", 0)?,
+            minor: get_u8(&value, "minor", 0)?,
+        },
+        "Handshake" => ClientMessage::Handshake {
+            min_major: get_u8(&value, "min_major", 0)?,
+            min_minor: get_u8(&value, "min_minor", 0)?,
+            max_major: get_u8(&value, "max_major", 0)?,
+            max_minor: get_u8(&value, "max_minor", 0)?,
+            capabilities: value.get("capabilities").and_then(|v| v.as_u64()).unwrap_or(0) as u32,
+        },
+        "MappingInfoChecksum" => ClientMessage::MappingInfoChecksum,
+        "MappingInfoRequest" => ClientMessage::MappingInfoRequest,
+        "MatchResume" => ClientMessage::MatchResume,
+        "TrainingResume" => ClientMessage::TrainingResume,
+        "Subscribe" => ClientMessage::Subscribe(Subscription {
+            entries: get_u8(&value, "entries", all.entries)?,
+            fields: get_u8(&value, "fields", all.fields)?,
+            decimation: get_u8(&value, "decimation", all.decimation)?,
+        }),
+        "Unsubscribe" => ClientMessage::Unsubscribe,
+        "ReplayListRequest" => ClientMessage::ReplayListRequest,
+        "ReplayDownloadRequest" => ClientMessage::ReplayDownloadRequest {
+            name: value.get("name").and_then(|v| v.as_str()).ok_or("missing \"name\"")?.to_string(),
+            offset: value.get("offset").and_then(|v| v.as_u64()).unwrap_or(0) as u32,
+        },
+        "ClientListRequest" => ClientMessage::ClientListRequest,
+        "Ping" => ClientMessage::Ping { timestamp: get_u64(&value, "timestamp")? },
+        "Pong" => ClientMessage::Pong { timestamp: get_u64(&value, "timestamp")? },
+        "UdpRequest" => ClientMessage::UdpRequest {
+            port: u16::try_from(get_u64(&value, "port")?).map_err(|_| "\"port\" is too large")?,
+        },
+        _ => return Err(format!("unknown message type \"{}\"", msg_type)),
+    };
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reframed_codec::{fields, PlayerInfo};
+
+    #[test]
+    fn messages_are_tagged_with_their_type() {
+        let value = message(&ServerMessage::Pong { timestamp: 1234 }, &[]);
+        assert_eq!(value, json!({ "type": "Pong", "timestamp": 1234 }));
+        assert_eq!(message(&ServerMessage::MatchEnd, &[]), json!({ "type": "MatchEnd" }));
+
+        let value = message(&ServerMessage::MatchStart(MatchStart {
+            stage_id: 3,
+            players: vec![PlayerInfo { entry_id: 0, fighter_kind: 2, fighter_skin: 1, name: "P1".to_string() }],
+        }), &[]);
+        assert_eq!(value["type"], "MatchStart");
+        assert_eq!(value["stage_name"], "Final Destination");
+        assert_eq!(value["players"][0]["fighter_kind_name"], "FIGHTER_KIND_LINK");
+        assert_eq!(value["players"][0]["name"], "P1");
+    }
+
+    #[test]
+    fn frame_message_shape() {
+        let fighter = |entry_id, status_kind| FighterState {
+            frame: 300,
+            entry_id: entry_id,
+            pos_x: -12.5,
+            damage: 42.0,
+            status_kind: status_kind,
+            motion_kind: 0x12_3456_789a,
+            hit_status: 1,
+            facing_right: true,
+            ..FighterState::default()
+        };
+        let frame = FrameState { frame: 300, fighters: vec![fighter(0, 2), fighter(1, 9)] };
+        let value = message(&ServerMessage::FrameState(frame.clone()), &[(0, 1), (1, 3)]);
+
+        assert_eq!(value["type"], "FrameState");
+        assert_eq!(value["frame"], 300);
+        let fighters = value["fighters"].as_array().unwrap();
+        assert_eq!(fighters.len(), 2);
+        assert_eq!(fighters[0]["entry_id"], 0);
+        assert_eq!(fighters[0]["pos_x"], -12.5);
+        assert_eq!(fighters[0]["damage"], 42.0);
+        assert_eq!(fighters[0]["facing_right"], true);
+        assert_eq!(fighters[0]["motion_kind"], "0x123456789a");
+        assert_eq!(fighters[0]["status_kind_name"], "FIGHTER_STATUS_KIND_ATTACK");
+        assert_eq!(fighters[0]["hit_status_name"], "HIT_STATUS_NORMAL");
+        // Unknown kinds are null rather than missing
+        assert_eq!(fighters[1]["status_kind"], 9);
+        assert!(fighters[1]["status_kind_name"].is_null());
+
+        // The extended layout looks the same
+        let extended = message(&ServerMessage::ExtendedFrameState { fields: fields::ALL, full_precision: true, state: frame }, &[(0, 1), (1, 3)]);
+        assert_eq!(extended, value);
+    }
+
+    #[test]
+    fn json_lines_request() {
+        let mut buf = b"json\r\n".to_vec();
+        assert!(is_json_lines_request(&take_line(&mut buf).unwrap()));
+        assert!(buf.is_empty());
+        assert!(!is_json_lines_request("jsonp"));
+        // Can't be the start of a binary message
+        assert!(reframed_codec::MessageType::try_from(JSON_LINES_REQUEST.as_bytes()[0]).is_err());
+    }
+
+    #[test]
+    fn lines_are_taken_once_complete() {
+        let mut buf = b"{\"type\":\"Subs".to_vec();
+        assert_eq!(take_line(&mut buf), Err(DecodeError::Incomplete));
+        assert_eq!(buf, b"{\"type\":\"Subs");
+
+        buf.extend_from_slice(b"cribe\", \"entries\": 3}\nPi");
+        let line = take_line(&mut buf).unwrap();
+        assert_eq!(buf, b"Pi");
+        let expected = Subscription { entries: 3, ..Subscription::all() };
+        assert_eq!(client_message(&line), Ok(Some(ClientMessage::Subscribe(expected))));
+
+        assert_eq!(take_line(&mut buf), Err(DecodeError::Incomplete));
+        buf.extend_from_slice(b"ng\n\n");
+        assert_eq!(client_message(&take_line(&mut buf).unwrap()), Ok(Some(ClientMessage::Ping { timestamp: 0 })));
+        assert_eq!(client_message(&take_line(&mut buf).unwrap()), Ok(None));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_rejected() {
+        let mut buf = vec![b' '; MAX_LINE_LENGTH - 1];
+        buf.push(b'\n');
+        assert_eq!(take_line(&mut buf).unwrap().len(), MAX_LINE_LENGTH - 1);
+
+        // Could still end with the next byte
+        let mut buf = vec![b' '; MAX_LINE_LENGTH];
+        assert_eq!(take_line(&mut buf), Err(DecodeError::Incomplete));
+        buf.push(b' ');
+        assert!(matches!(take_line(&mut buf), Err(DecodeError::Invalid(_))));
+
+        let mut buf = vec![b' '; MAX_LINE_LENGTH];
+        buf.push(b'\n');
+        assert!(matches!(take_line(&mut buf), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert!(client_message("{\"type\": \"Subscribe\"").is_err());
+        assert!(client_message("{\"entries\": 3}").is_err());
+        assert!(client_message("{\"type\": 3}").is_err());
+        assert!(client_message("Dance").is_err());
+        assert!(client_message("{\"type\": \"Subscribe\", \"entries\": 256}").is_err());
+        assert!(client_message("{\"type\": \"UdpRequest\", \"port\": 70000}").is_err());
+        assert!(client_message("{\"type\": \"ReplayDownloadRequest\"}").is_err());
+        // Not UTF-8, replaced rather than dropped
+        let mut buf = b"\xffPing\n".to_vec();
+        assert!(client_message(&take_line(&mut buf).unwrap()).is_err());
+    }
+}
@@ -1,39 +1,60 @@
-#![feature(proc_macro_hygiene)]
+#![cfg_attr(feature = "skyline", feature(proc_macro_hygiene))]
+// Fields are initialized with explicit names throughout the plugin
+#![allow(clippy::redundant_field_names)]
+#[cfg(feature = "skyline")]
 #[macro_use]
 
 mod constants;
-mod player_tags;
+mod backend;
+mod config;
+mod discovery;
+mod frame_batch;
+mod game_events;
 mod game_info;
+mod hitboxes;
+mod hitlag;
+mod json;
+mod logger;
+mod mapping_info;
+mod outbox;
+#[cfg(feature = "skyline")]
+mod player_tags;
 mod protocol;
-//mod replay;
+mod replay;
 mod server;
+#[cfg(feature = "skyline")]
+mod skyline_backend;
+#[cfg(feature = "sim")]
+pub mod sim;
 mod training_info;
 
-use training_info::TrainingInfo;
-use game_info::GameInfo;
-//use replay::ReplayManager;
 use lazy_static::lazy_static;
-use skyline;
-use acmd;
-use smash::app::{utility, sv_system, smashball};
-use smash::lib::{lua_const, L2CValue};
-use smash::app;
-use smash::app::lua_bind;
+
+// Without the game there has to be the simulator to get any data from
+#[cfg(not(any(feature = "skyline", feature = "sim")))]
+compile_error!("Either the skyline or the sim feature has to be enabled");
+
+#[cfg(feature = "skyline")]
+use skyline_backend::SkylineBackend;
+#[cfg(feature = "skyline")]
+use smash::app::sv_system;
+#[cfg(feature = "skyline")]
+use smash::lib::{L2CAgent, L2CValue};
+#[cfg(feature = "skyline")]
+use smash::app::{self, lua_bind, sv_animcmd, utility};
+#[cfg(feature = "skyline")]
+use smash::lib::lua_const;
+#[cfg(feature = "skyline")]
+use reframed_codec::Hitbox;
+#[cfg(feature = "skyline")]
 use smash::lua2cpp::{L2CFighterCommon, L2CFighterBase, L2CFighterBase_global_reset};
 use std::thread;
 use std::time::Duration;
 
-extern "C" {
-    #[link_name="\u{1}_ZN3app14sv_information27get_remaining_time_as_frameEv"]
-    pub fn get_remaining_time_as_frame() -> u32;
-
-    #[link_name="\u{1}_ZN3app14sv_information8stage_idEv"]
-    pub fn get_stage_id() -> i32;
-}
-
 lazy_static!{
     static ref SERVER: server::Server = server::Server::new();
 }
+#[cfg(feature = "skyline")]
 static mut FIGHTER_MANAGER_ADDR: usize = 0;
 
 /*
@@ -116,165 +137,146 @@ static mut FIGHTER_MANAGER_ADDR: usize = 0;
  * ...
  */
 
-#[skyline::hook(replace = L2CFighterBase_global_reset)]
-pub fn handle_fighter_global_reset(fighter: &mut L2CFighterBase) -> L2CValue {
-    let fighter_manager = unsafe { *(FIGHTER_MANAGER_ADDR as *mut *mut app::FighterManager) };
-    let is_ready_go = unsafe { lua_bind::FighterManager::is_ready_go(fighter_manager) };
-    let is_result_mode = unsafe { lua_bind::FighterManager::is_result_mode(fighter_manager) };
-    let is_training_mode = unsafe { smashball::is_training_mode() };
+/// Starts the server, the WebSocket endpoint and the discovery responder on
+/// background threads. They are restarted if they stop, e.g. because the
+/// network went away. Heartbeats to the connected clients are sent from
+/// another thread.
+fn start_server_threads() {
+    logger::init();
+    let retry_delay = Duration::from_secs(config::Config::get().retry_delay_secs());
 
-    if is_training_mode {
-        let mut training_info = TrainingInfo::get().lock().unwrap();
-        if !training_info.is_running() {
-            training_info.set_start_pending();
+    thread::spawn(move || {
+        loop {
+            SERVER.listen_for_incoming_connections();
+            thread::sleep(retry_delay)
         }
-    }
+    });
 
-    if !is_training_mode && !is_ready_go && is_result_mode {
-        let mut game_info = GameInfo::get().lock().unwrap();
-        if game_info.match_is_running() {
-            game_info.set_match_end();
-            protocol::broadcast_match_end(&SERVER);
-        }
+    thread::spawn(move || {
+        SERVER.send_heartbeats();
+    });
+
+    if config::Config::get().websocket() {
+        thread::spawn(move || {
+            loop {
+                SERVER.listen_for_websocket_connections();
+                thread::sleep(retry_delay)
+            }
+        });
     }
 
-    original!()(fighter)
+    if config::Config::get().discovery() {
+        thread::spawn(move || {
+            loop {
+                discovery::answer_probes();
+                thread::sleep(retry_delay)
+            }
+        });
+    }
 }
 
-pub fn once_per_frame_per_fighter(fighter : &mut L2CFighterCommon) {
-    let lua_state = fighter.lua_state_agent;
-    let module_accessor = unsafe { sv_system::battle_object_module_accessor(lua_state) };
+#[cfg(feature = "skyline")]
+fn backend() -> SkylineBackend {
     let fighter_manager = unsafe { *(FIGHTER_MANAGER_ADDR as *mut *mut app::FighterManager) };
-    let fighter_entry_id = unsafe { lua_bind::WorkModule::get_int(module_accessor, *lua_const::FIGHTER_INSTANCE_WORK_ID_INT_ENTRY_ID) as i32 };
-    let fighter_skin = unsafe { lua_bind::WorkModule::get_int(module_accessor, *lua_const::FIGHTER_INSTANCE_WORK_ID_INT_COLOR) as i32 };
-    let fighter_kind = unsafe { utility::get_kind(module_accessor) };
-    let is_ready_go = unsafe { lua_bind::FighterManager::is_ready_go(fighter_manager) };
-    let is_training_mode = unsafe { smashball::is_training_mode() };
-
-    if is_training_mode {
-        let mut training_info = TrainingInfo::get().lock().unwrap();
-
-        // Start notification logic. Have to collect info over multiple
-        // callbacks to this function before being able to send
-        // the start event, but the actual detection of the start
-        // event happens in the global_reset() hook.
-        if is_ready_go && training_info.is_start_pending() {
-            if fighter_entry_id == 0 {
-                training_info.set_player_info(fighter_kind);
-            }
-            if fighter_entry_id == 1 {
-                training_info.set_cpu_info(fighter_kind);
-            }
-            training_info.set_stage(unsafe { get_stage_id() });
-
-            if training_info.have_enough_info_to_start() {
-                training_info.start();
-                protocol::broadcast_training_start(&SERVER, &training_info);
-            }
-        }
+    SkylineBackend::new(fighter_manager)
+}
 
-        // Stop notification logic
-        if !is_ready_go && training_info.is_running() {
-            training_info.stop();
-            protocol::broadcast_training_end(&SERVER);
-        }
+#[cfg(feature = "skyline")]
+#[skyline::hook(replace = L2CFighterBase_global_reset)]
+pub fn handle_fighter_global_reset(fighter: &mut L2CFighterBase) -> L2CValue {
+    game_events::global_reset(&backend(), &SERVER);
+    original!()(fighter)
+}
 
-        // Don't send player states if training mode hasn't started
-        if !training_info.is_running() {
-            return;
-        }
-    } else {
-        // For now we only care about 1v1
-        let num_fighters = unsafe { lua_bind::FighterManager::entry_count(fighter_manager) };
-        if num_fighters != 2 {
-            return;
+// Items and projectiles go through the same modules and scripts, only
+// fighters are tracked
+#[cfg(feature = "skyline")]
+fn fighter_entry_id(module_accessor: *mut app::BattleObjectModuleAccessor) -> Option<i32> {
+    unsafe {
+        if utility::get_category(&mut *module_accessor) != *lua_const::BATTLE_OBJECT_CATEGORY_FIGHTER {
+            return None;
         }
+        Some(lua_bind::WorkModule::get_int(module_accessor, *lua_const::FIGHTER_INSTANCE_WORK_ID_INT_ENTRY_ID))
+    }
+}
 
-        let mut game_info = GameInfo::get().lock().unwrap();
+#[cfg(feature = "skyline")]
+fn track_hitlag(module_accessor: *mut app::BattleObjectModuleAccessor, frames: i32) {
+    if fighter_entry_id(module_accessor).is_some() {
+        let object_id = skyline_backend::battle_object_id(module_accessor);
+        hitlag::HitlagTracker::get().lock().unwrap().set_hitlag(object_id, frames.max(0) as u32);
+    }
+}
 
-        // Start notification logic. Have to collect info over multiple
-        // callbacks to this function before being able to send the
-        // start event.
-        if is_ready_go && !game_info.match_is_running() {
-            let player_tag = player_tags::get_name_for_slot(fighter_entry_id);
-            let player_tag = if player_tag.is_empty() {
-                format!("Player {}", fighter_entry_id + 1)
-            } else {
-                player_tag
-            };
+#[cfg(feature = "skyline")]
+#[skyline::hook(replace = lua_bind::StopModule::set_hit_stop_frame)]
+pub fn handle_set_hit_stop_frame(module_accessor: *mut app::BattleObjectModuleAccessor, frames: i32, arg3: bool) -> u64 {
+    track_hitlag(module_accessor, frames);
+    original!()(module_accessor, frames, arg3)
+}
 
-            game_info.set_player_info(
-                    fighter_entry_id,
-                    &player_tag,
-                    fighter_kind,
-                    fighter_skin);
-            game_info.set_stage(unsafe { get_stage_id() });
+#[cfg(feature = "skyline")]
+#[skyline::hook(replace = lua_bind::StopModule::set_hit_stop_frame_fix)]
+pub fn handle_set_hit_stop_frame_fix(module_accessor: *mut app::BattleObjectModuleAccessor, frames: i32) -> u64 {
+    track_hitlag(module_accessor, frames);
+    original!()(module_accessor, frames)
+}
 
-            if game_info.have_enough_info_to_start_match() {
-                game_info.set_match_start();
-                protocol::broadcast_match_start(&SERVER, &game_info);
+// Arguments of ATTACK in the move scripts, in order: id, part, bone, damage,
+// angle, kbg, fkb, bkb, size, x, y, z, x2, y2, z2, hitlag, sdi, clang,
+// facing, set weight, shield damage, trip, rehit, reflectable, absorbable,
+// flinchless, disable hitlag, direct, ground/air, hitbits, collision part,
+// friendly fire, effect, sound level, sound attribute, type
+#[cfg(feature = "skyline")]
+const ATTACK_ARGUMENTS: i32 = 36;
+
+#[cfg(feature = "skyline")]
+#[skyline::hook(replace = sv_animcmd::ATTACK)]
+pub fn handle_attack(lua_state: u64) {
+    unsafe {
+        let module_accessor = sv_system::battle_object_module_accessor(lua_state) as *mut app::BattleObjectModuleAccessor;
+        if fighter_entry_id(module_accessor).is_some() {
+            // Reading the arguments takes them off the Lua stack. All of them
+            // are read in order and pushed back for the game's ATTACK.
+            let mut agent = L2CAgent::new(lua_state);
+            let args: Vec<L2CValue> = (1..=ATTACK_ARGUMENTS).map(|i| agent.pop_lua_stack(i)).collect();
+            agent.clear_lua_stack();
+            for arg in args.iter() {
+                agent.push_lua_stack(&mut arg.clone());
             }
-        }
 
-        // Don't send player states if match hasn't started
-        if !game_info.match_is_running() {
-            return;
+            let hitbox = Hitbox {
+                id: args[0].get_int() as u8,
+                kind: args[32].get_int(),
+                bone: args[2].get_int(),
+                // Filled in every frame by the backend
+                bone_x: 0.0,
+                bone_y: 0.0,
+                offset_x: args[9].get_num(),
+                offset_y: args[10].get_num(),
+                offset_z: args[11].get_num(),
+                size: args[8].get_num(),
+                damage: args[3].get_num(),
+                angle: args[4].get_int() as u16,
+                knockback_growth: args[5].get_int() as u16,
+                fixed_knockback: args[6].get_int() as u16,
+                base_knockback: args[7].get_int() as u16,
+            };
+            let object_id = skyline_backend::battle_object_id(module_accessor);
+            hitboxes::HitboxTracker::get().lock().unwrap().define(object_id, hitbox);
         }
     }
+    original!()(lua_state);
+}
 
-    let fighter_information = unsafe {
-        lua_bind::FighterManager::get_fighter_information(fighter_manager, app::FighterEntryID(fighter_entry_id)) as *mut app::FighterInformation
-    };
-
-    // TODO
-    // Figure out when BoX sets start and end
-    // Iframes
-
-    let stock_count = unsafe { lua_bind::FighterInformation::stock_count(fighter_information) as u8 };
-    let frames_left = unsafe { get_remaining_time_as_frame() };
-    let fighter_status_kind = unsafe { lua_bind::StatusModule::status_kind(module_accessor) };
-    let fighter_motion_kind = unsafe{ lua_bind::MotionModule::motion_kind(module_accessor) };
-    let fighter_damage = unsafe { lua_bind::DamageModule::damage(module_accessor, 0) };
-    let fighter_shield_size = unsafe { lua_bind::WorkModule::get_float(module_accessor, *lua_const::FIGHTER_INSTANCE_WORK_ID_FLOAT_GUARD_SHIELD) };
-    let attack_connected = unsafe { lua_bind::AttackModule::is_infliction_status(module_accessor, *lua_const::COLLISION_KIND_MASK_HIT) };
-    let hitstun_left = unsafe { lua_bind::WorkModule::get_float(module_accessor, *lua_const::FIGHTER_INSTANCE_WORK_ID_FLOAT_DAMAGE_REACTION_FRAME) };
-    let pos_x = unsafe { lua_bind::PostureModule::pos_x(module_accessor) };
-    let pos_y = unsafe { lua_bind::PostureModule::pos_y(module_accessor) };
-    let facing = unsafe { lua_bind::PostureModule::lr(module_accessor) };
-    let iframe_status = unsafe { lua_bind::HitModule::get_total_status(module_accessor, 0) };
-
-    // Hitlag frames left on the opponent. Weird, seems to only work for
-    // "normal" attacks, not electric (such as pika dair, nair, tjolt). Same
-    // goes for the is_stop flag.
-    let hitlag_left = unsafe { lua_bind::StopModule::get_hit_stop_real_frame(module_accessor) };
-    //let is_stop = unsafe { lua_bind::StopModule::is_stop(module_accessor) };
-
-    // This is true if the opponent is in hitlag
-    let opponent_in_hitlag = unsafe { lua_bind::FighterStopModuleImpl::is_damage_stop(module_accessor) };
-
-    //let button = unsafe { lua_bind::ControlModule::get_button(module_accessor) };
-
-    //println!("hitlag_left: {}, opponent_in_hitlag: {}, button: {:#04x}", hitlag_left, opponent_in_hitlag, button as u32);
-
-    protocol::broadcast_fighter_info(&SERVER,
-        frames_left,
-        fighter_entry_id,
-        pos_x,
-        pos_y,
-        facing,
-        fighter_damage,
-        hitlag_left,
-        hitstun_left,
-        fighter_shield_size,
-        fighter_status_kind,
-        fighter_motion_kind,
-        iframe_status,
-        stock_count,
-        attack_connected,
-        opponent_in_hitlag,
-    );
+#[cfg(feature = "skyline")]
+pub fn once_per_frame_per_fighter(fighter : &mut L2CFighterCommon) {
+    let lua_state = fighter.lua_state_agent;
+    let module_accessor = unsafe { sv_system::battle_object_module_accessor(lua_state) as *mut app::BattleObjectModuleAccessor };
+    game_events::fighter_frame(&backend(), &module_accessor, &SERVER);
 }
 
+#[cfg(feature = "skyline")]
 fn nro_main(nro: &skyline::nro::NroInfo<'_>) {
     match nro.name {
         "common" => {
@@ -286,6 +288,7 @@ fn nro_main(nro: &skyline::nro::NroInfo<'_>) {
     }
 }
 
+#[cfg(feature = "skyline")]
 #[skyline::main(name = "ReFramed")]
 pub fn main() {
     skyline::nro::add_hook(nro_main).unwrap();
@@ -296,12 +299,11 @@ pub fn main() {
         );
     }
     acmd::add_custom_hooks!(once_per_frame_per_fighter);
+    skyline::install_hooks!(
+        handle_set_hit_stop_frame,
+        handle_set_hit_stop_frame_fix,
+        handle_attack,
+    );
 
-    std::thread::spawn(move || {
-        loop {
-            SERVER.listen_for_incoming_connections();
-            thread::sleep(Duration::from_secs(10))
-        }
-    });
+    start_server_threads();
 }
-
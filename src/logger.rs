@@ -0,0 +1,39 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::config::Config;
+
+/*
+ * Prints log messages to stdout with the plugin's prefix, which is where
+ * `cargo skyline run` picks them up.
+ */
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Info => println!("[ReFramed] {}", record.args()),
+            level => println!("[ReFramed] {}: {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger and loads the config, which sets the log level.
+/// Problems with the config file are logged at the default level.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    log::set_max_level(LevelFilter::Info);
+    log::set_max_level(Config::get().log_level());
+}
@@ -0,0 +1,115 @@
+use lazy_static::lazy_static;
+use reframed_codec::MappingInfoHasher;
+
+lazy_static!{
+    // Resolving all of the lua constants is slow, and the tables never change
+    // at runtime, so only do it once
+    static ref MAPPING_INFO: MappingInfo = MappingInfo::load();
+}
+
+// Fighter kind of the statuses in fighter_status_kinds that every fighter has
+const COMMON_FIGHTER_KIND: u8 = 255;
+
+/// The tables clients need to turn the numbers in fighter states into names.
+/// On the console they come from constants.rs, the simulator has its own.
+pub struct MappingInfo {
+    pub fighter_kinds: Vec<(u8, &'static str)>,
+    pub stage_kinds: Vec<(u16, &'static str)>,
+    pub fighter_status_kinds: Vec<(u8, u16, &'static str)>,
+    pub hit_status_kinds: Vec<(u8, &'static str)>,
+    checksum: u32,
+}
+
+impl MappingInfo {
+    pub fn get() -> &'static Self {
+        &MAPPING_INFO
+    }
+
+    pub fn new(
+        fighter_kinds: Vec<(u8, &'static str)>,
+        stage_kinds: Vec<(u16, &'static str)>,
+        fighter_status_kinds: Vec<(u8, u16, &'static str)>,
+        hit_status_kinds: Vec<(u8, &'static str)>
+    ) -> Self {
+        let mut hasher = MappingInfoHasher::new();
+        for (kind, name) in fighter_kinds.iter() {
+            hasher.add_fighter_kind(*kind, name);
+        }
+        for (kind, name) in stage_kinds.iter() {
+            hasher.add_stage_kind(*kind, name);
+        }
+        for (fighter, status, name) in fighter_status_kinds.iter() {
+            hasher.add_fighter_status_kind(*fighter, *status, name);
+        }
+        for (kind, name) in hit_status_kinds.iter() {
+            hasher.add_hit_status_kind(*kind, name);
+        }
+
+        Self {
+            fighter_kinds: fighter_kinds,
+            stage_kinds: stage_kinds,
+            fighter_status_kinds: fighter_status_kinds,
+            hit_status_kinds: hit_status_kinds,
+            checksum: hasher.finish(),
+        }
+    }
+
+    #[cfg(feature = "skyline")]
+    fn load() -> Self {
+        use crate::constants;
+
+        Self::new(
+            constants::FIGHTER_KINDS.iter()
+                .map(|(kind, name)| (kind.as_lua_int().get_int() as u8, *name))
+                .collect(),
+            constants::STAGE_KINDS.iter()
+                .map(|(kind, name)| (*kind as u16, *name))
+                .collect(),
+            constants::FIGHTER_STATUS_KINDS.iter()
+                .map(|(status, fighter, name)| (
+                    fighter.as_lua_int().get_int() as u8,
+                    status.as_lua_int().get_int() as u16,
+                    *name))
+                .collect(),
+            constants::HIT_STATUS_KINDS.iter()
+                .map(|(kind, name)| (kind.as_lua_int().get_int() as u8, *name))
+                .collect()
+        )
+    }
+
+    #[cfg(all(feature = "sim", not(feature = "skyline")))]
+    fn load() -> Self {
+        crate::sim::mapping_info()
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    pub fn fighter_kind_name(&self, fighter_kind: u8) -> Option<&'static str> {
+        self.fighter_kinds.iter()
+            .find(|(kind, _)| *kind == fighter_kind)
+            .map(|(_, name)| *name)
+    }
+
+    pub fn stage_kind_name(&self, stage_kind: u16) -> Option<&'static str> {
+        self.stage_kinds.iter()
+            .find(|(kind, _)| *kind == stage_kind)
+            .map(|(_, name)| *name)
+    }
+
+    /// Falls back to the statuses all fighters share if the fighter doesn't
+    /// have its own status with this value
+    pub fn fighter_status_kind_name(&self, fighter_kind: Option<u8>, status_kind: u16) -> Option<&'static str> {
+        let find = |fighter: u8| self.fighter_status_kinds.iter()
+            .find(|(kind, status, _)| *kind == fighter && *status == status_kind)
+            .map(|(_, _, name)| *name);
+        fighter_kind.and_then(find).or_else(|| find(COMMON_FIGHTER_KIND))
+    }
+
+    pub fn hit_status_kind_name(&self, hit_status_kind: u8) -> Option<&'static str> {
+        self.hit_status_kinds.iter()
+            .find(|(kind, _)| *kind == hit_status_kind)
+            .map(|(_, name)| *name)
+    }
+}
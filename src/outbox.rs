@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use log::warn;
+
+/*
+ * Outbound queue of a single client. Broadcasts are called from the game's
+ * per-frame hook and must never block on a slow client, so they only push
+ * already encoded messages in here and a writer thread per client does the
+ * actual sending.
+ *
+ * When a client can't keep up, the oldest frame states are dropped. Start
+ * and end events and replies to requests are never dropped.
+ */
+
+// About 4 seconds worth of frame states
+const CAPACITY: usize = 256;
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    droppable: bool,
+}
+
+struct Queue {
+    entries: VecDeque<Entry>,
+    closed: bool,
+    // Only log once each time the client starts falling behind
+    dropping: bool,
+}
+
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected")
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(Queue {
+                entries: VecDeque::new(),
+                closed: false,
+                dropping: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Never blocks. Messages that aren't droppable are always queued, even
+    /// if the queue is full.
+    pub fn push(&self, data: Arc<Vec<u8>>, droppable: bool) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Err(closed_error());
+        }
+
+        if droppable && queue.entries.len() >= CAPACITY {
+            if !queue.dropping {
+                warn!("Client can't keep up, dropping frames");
+                queue.dropping = true;
+            }
+            match queue.entries.iter().position(|e| e.droppable) {
+                Some(oldest) => { queue.entries.remove(oldest); },
+                // Everything queued is important, drop the new one instead
+                None => return Ok(()),
+            }
+        }
+
+        queue.entries.push_back(Entry { data: data, droppable: droppable });
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Waits until there is space in the queue. For long replies like the
+    /// mapping info or replay downloads, which would otherwise be read into
+    /// memory all at once.
+    pub fn push_wait(&self, data: Arc<Vec<u8>>) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.closed && queue.entries.len() >= CAPACITY {
+            queue = self.not_full.wait(queue).unwrap();
+        }
+        if queue.closed {
+            return Err(closed_error());
+        }
+
+        queue.entries.push_back(Entry { data: data, droppable: false });
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next message. Returns None once the outbox is closed
+    /// and everything in it was sent.
+    pub fn pop(&self) -> Option<Arc<Vec<u8>>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(entry) = queue.entries.pop_front() {
+                if queue.entries.is_empty() {
+                    queue.dropping = false;
+                }
+                self.not_full.notify_one();
+                return Some(entry.data);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.not_empty.wait(queue).unwrap();
+        }
+    }
+
+    /// Nothing can be queued after this. Whatever is already queued is still
+    /// sent, e.g. a rejection right before disconnecting a client.
+    pub fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().unwrap().closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn data(n: usize) -> Arc<Vec<u8>> {
+        Arc::new((n as u32).to_be_bytes().to_vec())
+    }
+
+    fn pop_all(outbox: &Outbox) -> Vec<Arc<Vec<u8>>> {
+        outbox.close();
+        std::iter::from_fn(|| outbox.pop()).collect()
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_frame() {
+        let outbox = Outbox::new();
+        outbox.push(data(0), false).unwrap();
+        for n in 1..CAPACITY {
+            outbox.push(data(n), true).unwrap();
+        }
+        outbox.push(data(CAPACITY), true).unwrap();
+
+        let popped = pop_all(&outbox);
+        assert_eq!(popped.len(), CAPACITY);
+        assert_eq!(popped[0], data(0));
+        assert_eq!(popped[1], data(2));
+        assert_eq!(popped[CAPACITY - 1], data(CAPACITY));
+    }
+
+    #[test]
+    fn events_are_never_dropped() {
+        let outbox = Outbox::new();
+        for n in 0..CAPACITY {
+            outbox.push(data(n), false).unwrap();
+        }
+        // Nothing to make room with, the new frame is dropped instead
+        outbox.push(data(CAPACITY), true).unwrap();
+        // Events are queued past the capacity
+        outbox.push(data(CAPACITY + 1), false).unwrap();
+
+        let popped = pop_all(&outbox);
+        assert_eq!(popped.len(), CAPACITY + 1);
+        assert_eq!(popped[CAPACITY - 1], data(CAPACITY - 1));
+        assert_eq!(popped[CAPACITY], data(CAPACITY + 1));
+    }
+
+    #[test]
+    fn push_wait_waits_for_space() {
+        let outbox = Arc::new(Outbox::new());
+        for n in 0..CAPACITY {
+            outbox.push(data(n), true).unwrap();
+        }
+
+        let pusher = {
+            let outbox = outbox.clone();
+            thread::spawn(move || outbox.push_wait(data(CAPACITY)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pusher.is_finished());
+
+        assert_eq!(outbox.pop(), Some(data(0)));
+        pusher.join().unwrap().unwrap();
+        let popped = pop_all(&outbox);
+        assert_eq!(popped.len(), CAPACITY);
+        assert_eq!(popped[CAPACITY - 1], data(CAPACITY));
+    }
+
+    #[test]
+    fn close_sends_what_is_queued() {
+        let outbox = Arc::new(Outbox::new());
+        outbox.push(data(0), false).unwrap();
+        outbox.close();
+        assert!(outbox.push(data(1), false).is_err());
+        assert!(outbox.push_wait(data(1)).is_err());
+        assert_eq!(outbox.pop(), Some(data(0)));
+        assert_eq!(outbox.pop(), None);
+
+        // A writer waiting on an empty queue wakes up when it's closed
+        let outbox = Arc::new(Outbox::new());
+        let writer = {
+            let outbox = outbox.clone();
+            thread::spawn(move || outbox.pop())
+        };
+        thread::sleep(Duration::from_millis(50));
+        outbox.close();
+        assert_eq!(writer.join().unwrap(), None);
+    }
+}
@@ -1,306 +1,390 @@
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use crate::config::Config;
 use crate::game_info::GameInfo;
 use crate::training_info::TrainingInfo;
-use crate::server::Server;
-use crate::constants;
-use crc::{Crc, CRC_32_CKSUM};
-use skyline::libc;
-
-#[derive(IntoPrimitive, TryFromPrimitive)]
-#[repr(u8)]
-pub enum MessageType {
-    ProtocolVersion,
-
-    MappingInfoChecksum,
-    MappingInfoRequest,
-    MappingInfoFighterKinds,
-    MappingInfoFighterStatusKinds,
-    MappingInfoStageKinds,
-    MappingInfoHitStatusKinds,
-    MappingInfoRequestComplete,
-
-
+use crate::server::{Client, Server};
+use crate::replay::{self, ReplayManager};
+use crate::frame_batch::FrameBatch;
+use crate::mapping_info::MappingInfo;
+use reframed_codec::{
+    capabilities,
+    fields,
+    ClientInfo,
+    FighterState,
+    FrameState,
+    Hitbox,
     MatchStart,
-    MatchResume,
-    MatchEnd,
+    PlayerInfo,
+    ServerMessage,
+    Subscription,
     TrainingStart,
-    TrainingResume,
-    TrainingReset,
-    TrainingEnd,
-
-    FighterState,
+};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use log::{debug, info, warn};
+
+// Size of the data in each ReplayDownloadChunk message
+const REPLAY_CHUNK_SIZE: usize = 4096;
+
+// Bare message type followed by an implicit payload
+const PROTOCOL_VERSION_LEGACY: (u8, u8) = (1, 1);
+// Every message has a header with the payload length, see reframed_codec::frame
+const PROTOCOL_VERSION_FRAMED: (u8, u8) = (1, 2);
+
+const PROTOCOL_VERSION_MIN: (u8, u8) = PROTOCOL_VERSION_LEGACY;
+pub const PROTOCOL_VERSION_MAX: (u8, u8) = PROTOCOL_VERSION_FRAMED;
+
+// Capabilities this server can enable, see reframed_codec::capabilities
+const SERVER_CAPABILITIES: u32 = capabilities::COMPRESSION
+    | capabilities::EXTENDED_FIGHTER_FIELDS
+    | capabilities::MULTI_PLAYER
+    | capabilities::HEARTBEAT
+    | capabilities::FULL_PRECISION
+    | capabilities::HITBOXES;
+// Capabilities that change message payloads, which unframed clients wouldn't
+// be able to parse
+const FRAMED_ONLY_CAPABILITIES: u32 = capabilities::COMPRESSION
+    | capabilities::EXTENDED_FIGHTER_FIELDS
+    | capabilities::FULL_PRECISION
+    | capabilities::HITBOXES;
+
+// How many messages to encode into one send() when sending long lists
+const MESSAGES_PER_SEND: usize = 64;
+
+// Waits if the client's outbox is full. Must be called without holding
+// the client's lock, or broadcasts would block along with it
+fn send_message(client: &Mutex<Client>, msg: &ServerMessage) -> io::Result<()> {
+    let (data, outbox) = {
+        let mut client = client.lock().unwrap();
+        if !client.accepts(msg) {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        client.encode(msg, &mut buf);
+        (buf, client.outbox().clone())
+    };
+    outbox.push_wait(Arc::new(data))
 }
 
-fn send_bytes(socket: libc::c_int, bytes: &[u8]) -> Result<(), i64> {
-    unsafe {
-        let result = libc::send(socket, bytes.as_ptr() as *const _, bytes.len(), 0);
-        if result < 0 {
-            Err(*libc::errno_loc())
-        } else {
-            Ok(())
-        }
+/// Sends a long list of messages in batches, so that they don't all have to
+/// be encoded up front
+fn send_messages(client: &Mutex<Client>, msgs: &[ServerMessage]) -> io::Result<()> {
+    for batch in msgs.chunks(MESSAGES_PER_SEND) {
+        let (data, outbox) = {
+            let mut client = client.lock().unwrap();
+            let mut buf = Vec::new();
+            for msg in batch.iter() {
+                client.encode(msg, &mut buf);
+            }
+            (buf, client.outbox().clone())
+        };
+        outbox.push_wait(Arc::new(data))?;
     }
+    Ok(())
 }
 
-pub fn send_protocol_version(socket: libc::c_int) -> Result<(), i64> {
-    let major = 0x01;
-    let minor = 0x01;
-    println!("[ReFramed] Sending protocol version {}.{}", major, minor);
-    send_bytes(socket, &[MessageType::ProtocolVersion.into(), major, minor])?;
+pub fn send_protocol_version(client: &Mutex<Client>) -> io::Result<()> {
+    let (major, minor) = PROTOCOL_VERSION_LEGACY;
+    debug!("Sending protocol version {}.{}", major, minor);
+    send_message(client, &ServerMessage::ProtocolVersion { major: major, minor: minor })?;
     Ok(())
 }
 
-fn calc_mapping_info_checksum() -> u32 {
-    let crc = Crc::<u32>::new(&CRC_32_CKSUM);
-    let mut digest = crc.digest();
+// Clients asking for a version without a handshake get the newest one this
+// server has that isn't newer than what they asked for, or the oldest one
+fn select_protocol_version(client_version: (u8, u8)) -> (u8, u8) {
+    if client_version >= PROTOCOL_VERSION_FRAMED {
+        PROTOCOL_VERSION_FRAMED
+    } else {
+        PROTOCOL_VERSION_LEGACY
+    }
+}
 
-    for (kind, name) in constants::FIGHTER_KINDS.iter() {
-        digest.update(&(kind.as_lua_int().get_int() as u8).to_be_bytes());
-        digest.update(name.as_bytes());
+pub fn negotiate_protocol_version(client: &Mutex<Client>, client_major: u8, client_minor: u8) -> io::Result<()> {
+    let (major, minor) = select_protocol_version((client_major, client_minor));
+    info!("Client supports protocol version {}.{}, using {}.{}",
+        client_major, client_minor, major, minor);
+
+    // The reply is always in the old format so that the client can parse it
+    // no matter which version it asked for. Everything after it is framed.
+    let mut client = client.lock().unwrap();
+    client.send_unframed(&ServerMessage::ProtocolVersion { major: major, minor: minor })?;
+    if (major, minor) >= PROTOCOL_VERSION_FRAMED {
+        client.set_framed();
     }
+    Ok(())
+}
 
-    for (kind, name) in constants::STAGE_KINDS.iter() {
-        digest.update(&(*kind as u16).to_be_bytes());
-        digest.update(name.as_bytes());
+// Returns the protocol version and capabilities to use, or why there are
+// none both sides support
+fn negotiate(client_min: (u8, u8), client_max: (u8, u8), client_capabilities: u32) -> Result<((u8, u8), u32), String> {
+    // Pick the highest version both sides support
+    let version = client_max.min(PROTOCOL_VERSION_MAX);
+    if version < client_min.max(PROTOCOL_VERSION_MIN) {
+        return Err(format!("Server supports protocol versions {}.{} to {}.{}, client supports {}.{} to {}.{}",
+            PROTOCOL_VERSION_MIN.0, PROTOCOL_VERSION_MIN.1,
+            PROTOCOL_VERSION_MAX.0, PROTOCOL_VERSION_MAX.1,
+            client_min.0, client_min.1, client_max.0, client_max.1));
     }
 
-    for (status, fighter, name) in constants::FIGHTER_STATUS_KINDS.iter() {
-        digest.update(&(fighter.as_lua_int().get_int() as u8).to_be_bytes());
-        digest.update(&(status.as_lua_int().get_int() as u16).to_be_bytes());
-        digest.update(name.as_bytes());
+    let mut enabled = client_capabilities & SERVER_CAPABILITIES;
+    if version < PROTOCOL_VERSION_FRAMED {
+        enabled &= !FRAMED_ONLY_CAPABILITIES;
+    }
+    // Full precision values only exist in the extended layout
+    if !capabilities::has(enabled, capabilities::EXTENDED_FIGHTER_FIELDS) {
+        enabled &= !capabilities::FULL_PRECISION;
     }
+    Ok((version, enabled))
+}
+
+pub fn handshake(client: &Mutex<Client>, client_min: (u8, u8), client_max: (u8, u8), client_capabilities: u32) -> io::Result<()> {
+    info!("Client supports protocol versions {}.{} to {}.{}, capabilities 0x{:08x}",
+        client_min.0, client_min.1, client_max.0, client_max.1, client_capabilities);
 
-    for (kind, name) in constants::HIT_STATUS_KINDS.iter() {
-        digest.update(&(kind.as_lua_int().get_int() as u8).to_be_bytes());
-        digest.update(name.as_bytes());
+    let mut client = client.lock().unwrap();
+
+    let (version, enabled) = match negotiate(client_min, client_max, client_capabilities) {
+        Ok(negotiated) => negotiated,
+        Err(reason) => {
+            warn!("Rejecting client: {}", reason);
+            client.send_unframed(&ServerMessage::HandshakeReject { reason: reason.clone() })?;
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason));
+        }
+    };
+    let framed = version >= PROTOCOL_VERSION_FRAMED;
+    info!("Using protocol version {}.{}, capabilities 0x{:08x}", version.0, version.1, enabled);
+
+    // Unframed for the same reason as in negotiate_protocol_version()
+    client.send_unframed(&ServerMessage::HandshakeAccept {
+        major: version.0,
+        minor: version.1,
+        capabilities: enabled,
+    })?;
+    client.set_capabilities(enabled);
+    if framed {
+        client.set_framed();
     }
+    Ok(())
+}
 
-    digest.finalize()
+pub fn mapping_info_checksum() -> u32 {
+    MappingInfo::get().checksum()
 }
 
-pub fn send_mapping_info_checksum(socket: libc::c_int) -> Result<(), i64> {
-    let [h0, h1, h2, h3] = calc_mapping_info_checksum().to_be_bytes();
-    println!("[ReFramed] Sending mapping info checksum");
-    send_bytes(socket, &[MessageType::MappingInfoChecksum.into(), h0, h1, h2, h3])?;
+pub fn send_mapping_info_checksum(client: &Mutex<Client>) -> io::Result<()> {
+    debug!("Sending mapping info checksum");
+    send_message(client, &ServerMessage::MappingInfoChecksum(mapping_info_checksum()))?;
     Ok(())
 }
 
-pub fn send_mapping_info(socket: libc::c_int) -> Result<(), i64> {
-    println!("[ReFramed] Sending mapping info");
+pub fn send_mapping_info(client: &Mutex<Client>) -> io::Result<()> {
+    debug!("Sending mapping info");
 
-    let [h0, h1, h2, h3] = calc_mapping_info_checksum().to_be_bytes();
-    send_bytes(socket, &[MessageType::MappingInfoRequest.into(), h0, h1, h2, h3])?;
+    send_message(client, &ServerMessage::MappingInfoRequest(mapping_info_checksum()))?;
 
-    send_fighter_kind_constants(socket)?;
-    send_fighter_status_kind_constants(socket)?;
-    send_stage_constants(socket)?;
-    send_hit_status_constants(socket)?;
-    send_bytes(socket, &[MessageType::MappingInfoRequestComplete.into()])?;
+    send_fighter_kind_constants(client)?;
+    send_fighter_status_kind_constants(client)?;
+    send_stage_constants(client)?;
+    send_hit_status_constants(client)?;
+    send_message(client, &ServerMessage::MappingInfoRequestComplete)?;
     Ok(())
 }
 
-fn match_start_payload(info: &GameInfo) -> Vec<u8> {
-    let stage_id = info.get_stage() as u16;
-    let stage_id_u = ((stage_id >> 8) & 0xFF) as u8;
-    let stage_id_l = ((stage_id >> 0) & 0xFF) as u8;
-    let p1name_bytes = info.p1_name().as_bytes();
-    let p2name_bytes = info.p2_name().as_bytes();
-
-    let mut data = vec![
-        stage_id_u, stage_id_l,
-        2,  // 2 players, hardcoded for now
-        info.p1_entry_id() as u8,
-        info.p1_fighter_kind() as u8,
-        info.p1_fighter_skin() as u8,
-        info.p2_entry_id() as u8,
-        info.p2_fighter_kind() as u8,
-        info.p2_fighter_skin() as u8
-    ];
-    data.push(p1name_bytes.len() as u8);
-    data.extend_from_slice(p1name_bytes);
-    data.push(p2name_bytes.len() as u8);
-    data.extend_from_slice(p2name_bytes);
+fn match_start_payload(info: &GameInfo) -> MatchStart {
+    MatchStart {
+        stage_id: info.get_stage() as u16,
+        players: info.players().iter().map(|p| PlayerInfo {
+            entry_id: p.entry_id() as u8,
+            fighter_kind: p.fighter_kind() as u8,
+            fighter_skin: p.fighter_skin() as u8,
+            name: p.name().clone(),
+        }).collect(),
+    }
+}
 
-    data
+fn players_summary(info: &GameInfo) -> String {
+    info.players().iter()
+        .map(|p| format!("{} ({})", p.name(), p.fighter_kind()))
+        .collect::<Vec<String>>()
+        .join(", ")
 }
 
 pub fn broadcast_match_start(server: &Server, info: &GameInfo) {
-    let mut data = match_start_payload(&info);
-    data.insert(0, MessageType::MatchStart.into());
-    println!("[ReFramed] Match start: stage: {}, p1: {} ({}), p2: {} ({})",
+    let msg = ServerMessage::MatchStart(match_start_payload(info));
+    info!("Match start: stage: {}, players: {}",
         info.get_stage(),
-        info.p1_name(), info.p1_fighter_kind(),
-        info.p2_name(), info.p2_fighter_kind()
+        players_summary(info)
     );
 
-    server.broadcast(&data);
+    if Config::get().stream_matches() {
+        server.broadcast(&msg);
+    }
+    ReplayManager::get().lock().unwrap().start_recording(mapping_info_checksum(), &msg);
 }
 
-pub fn send_match_resume(socket: libc::c_int) -> Result<(), i64> {
+pub fn broadcast_match_end(server: &Server) {
+    info!("Match end");
+    flush_frame_state(server, false);
+    let msg = ServerMessage::MatchEnd;
+    if Config::get().stream_matches() {
+        server.broadcast(&msg);
+    }
+    ReplayManager::get().lock().unwrap().stop_recording(&msg);
+}
+
+/// Sends whatever is currently running and enables broadcasts. Holding the
+/// locks of the game/training info while doing so guarantees that the client
+/// can't miss a start or end event in between.
+pub fn subscribe(client: &Mutex<Client>, subscription: Subscription) -> io::Result<()> {
     let game_info = GameInfo::get().lock().unwrap();
-    if game_info.match_is_running() {
-        let mut data = match_start_payload(&game_info);
-        data.insert(0, MessageType::MatchResume.into());
+    let training_info = TrainingInfo::get().lock().unwrap();
+    let mut client = client.lock().unwrap();
 
-        println!("[ReFramed] Match resume: stage: {}, p1: {} ({}), p2: {} ({})",
+    info!("Client subscribed to entries 0x{:02x}, fields 0x{:02x}, every {} frame(s)",
+        subscription.entries, subscription.fields, subscription.decimation.max(1));
+    client.set_subscription(subscription);
+
+    if game_info.match_is_running() && Config::get().stream_matches() {
+        info!("Match resume: stage: {}, players: {}",
             game_info.get_stage(),
-            game_info.p1_name(), game_info.p1_fighter_kind(),
-            game_info.p2_name(), game_info.p2_fighter_kind()
+            players_summary(&game_info)
         );
-        send_bytes(socket, &data)?;
+        client.send(&ServerMessage::MatchResume(match_start_payload(&game_info)))?;
     }
+    if training_info.is_running() && Config::get().stream_training() {
+        info!("Training resume: stage: {}, p1: {}, cpu: {}",
+            training_info.get_stage(),
+            training_info.p1_fighter_kind(),
+            training_info.cpu_fighter_kind()
+        );
+        client.send(&ServerMessage::TrainingResume(training_start_payload(&training_info)))?;
+    }
+
+    client.set_allow_broadcasts(true);
     Ok(())
 }
 
-pub fn broadcast_match_end(server: &Server) {
-    println!("[ReFramed] Match end");
-    server.broadcast(&[MessageType::MatchEnd.into()]);
+pub fn unsubscribe(client: &Mutex<Client>) -> io::Result<()> {
+    client.lock().unwrap().set_allow_broadcasts(false);
+    Ok(())
 }
 
-fn training_start_payload(info: &TrainingInfo) -> [u8; 4] {
-    let stage_id = info.get_stage();
-    let stage_id_u = ((stage_id >> 8) & 0xFF) as u8;
-    let stage_id_l = ((stage_id >> 0) & 0xFF) as u8;
-    [
-        stage_id_u, stage_id_l,
-        info.p1_fighter_kind() as u8,
-        info.cpu_fighter_kind() as u8
-    ]
+fn training_start_payload(info: &TrainingInfo) -> TrainingStart {
+    TrainingStart {
+        stage_id: info.get_stage() as u16,
+        p1_fighter_kind: info.p1_fighter_kind() as u8,
+        cpu_fighter_kind: info.cpu_fighter_kind() as u8,
+    }
 }
 
 pub fn broadcast_training_start(server: &Server, info: &TrainingInfo) {
-    println!("[ReFramed] Training start: stage: {}, p1: {}, cpu: {}",
+    info!("Training start: stage: {}, p1: {}, cpu: {}",
         info.get_stage(),
         info.p1_fighter_kind(),
         info.cpu_fighter_kind()
     );
 
-    let mut data: Vec<u8> = Vec::new();
-    data.push(MessageType::TrainingStart.into());
-    data.extend_from_slice(&training_start_payload(&info));
-    server.broadcast(&data);
+    if Config::get().stream_training() {
+        server.broadcast(&ServerMessage::TrainingStart(training_start_payload(info)));
+    }
 }
 
-pub fn send_training_resume(socket: libc::c_int) -> Result<(), i64> {
-    let training_info = TrainingInfo::get().lock().unwrap();
-    if training_info.is_running() {
-        let mut data: Vec<u8> = Vec::new();
-        data.push(MessageType::TrainingResume.into());
-        data.extend_from_slice(&training_start_payload(&training_info));
+pub fn broadcast_training_end(server: &Server) {
+    info!("Training end");
+    flush_frame_state(server, true);
+    if Config::get().stream_training() {
+        server.broadcast(&ServerMessage::TrainingEnd);
+    }
+}
 
-        println!("[ReFramed] Training resume: stage: {}, p1: {}, cpu: {}",
-            training_info.get_stage(),
-            training_info.p1_fighter_kind(),
-            training_info.cpu_fighter_kind()
-        );
-        send_bytes(socket, &data)?;
+fn broadcast_frame_state(server: &Server, training: bool, frame: FrameState) {
+    if Config::get().stream_frame_states(training) {
+        server.broadcast(&ServerMessage::FrameState(frame));
     }
-    Ok(())
 }
 
-pub fn broadcast_training_end(server: &Server) {
-    println!("[ReFramed] Training end");
-    server.broadcast(&[MessageType::TrainingEnd.into()]);
-}
-
-pub fn broadcast_fighter_info(
-    server: &Server,
-    frame: u32,
-    entry_id: i32,
-    pos_x: f32,
-    pos_y: f32,
-    facing: f32,
-    damage: f32,
-    _hitlag_left: u64,  // This value doesn't work for all attacks, so better not send it until we find a solution
-    hitstun_left: f32,
-    shield_size: f32,
-    status_kind: i32,
-    motion_kind: u64,
-    hit_status: u64,
-    stock_count: u8,
-    attack_connected: bool,
-    opponent_in_hitlag: bool
-) {
-    // We don't really need to know damage beyond 0.02% accuracy and the upper
-    // limit is 999.99%, so multiplying it by 50 lets us store it in one u16
-    let damage_int = (damage*50.0) as u16;
-    let [damage0, damage1] = damage_int.to_be_bytes();
-
-    // Shield sizes seem to be around 50ish -> 10000 max leaves some room
-    let shield_int = (shield_size*200.0) as u16;
-    let [shield0, shield1] = shield_int.to_be_bytes();
-
-    // Can't think of any move with hitstun over 1 second (60)
-    // 60*100 = 60000
-    let hitstun_int = (hitstun_left*100.0) as u16;
-    let [hitstun0, hitstun1] = hitstun_int.to_be_bytes();
-
-    // Highest status value seems to be 872 (I'm looking at you kirby)
-    let [status0, status1] = (status_kind as u16).to_be_bytes();
-
-    // Motion kinds are hash40 values which use 40 bits (5 bytes)
-    let [_, _, _, motion0, motion1, motion2, motion3, motion4] = motion_kind.to_be_bytes();
-
-    // Booleans can be combined into a single u8
-    let flags = 
-        ((attack_connected as u8) << 0)
-      | ((if facing > 0.0 {1} else {0}) << 1)
-      | ((opponent_in_hitlag as u8) << 2);
-
-    // Other 
-    let [frame0, frame1, frame2, frame3] = frame.to_be_bytes();
-    let [posx0, posx1, posx2, posx3] = pos_x.to_be_bytes();
-    let [posy0, posy1, posy2, posy3] = pos_y.to_be_bytes();
-
-    server.broadcast(&[
-        MessageType::FighterState.into(),
-        frame0, frame1, frame2, frame3,
-        entry_id as u8,
-        posx0, posx1, posx2, posx3,
-        posy0, posy1, posy2, posy3,
-        damage0, damage1,
-        hitstun0, hitstun1,
-        shield0, shield1,
-        status0, status1,
-        motion0, motion1, motion2, motion3, motion4,
-        hit_status as u8,
-        stock_count,
-        flags
-    ]);
-}
-
-fn send_fighter_kind_constants(socket: libc::c_int) -> Result<(), i64> {
-    let mut buf = vec![];
-    for (kind, name) in constants::FIGHTER_KINDS.iter() {
-        let name_bytes = name.as_bytes();
-        let data = &[MessageType::MappingInfoFighterKinds.into(), kind.as_lua_int().get_int() as u8, name_bytes.len() as u8];
-        let data = &[data, name_bytes].concat();
-        buf.extend_from_slice(data);
-    }
-    send_bytes(socket, &buf)?;
-    Ok(())
+// Sends the fighters of the last frame that haven't been sent yet, so that
+// they don't end up after the end event
+fn flush_frame_state(server: &Server, training: bool) {
+    let frame = FrameBatch::get().lock().unwrap().flush();
+    if let Some(frame) = frame {
+        broadcast_frame_state(server, training, frame);
+    }
 }
 
-fn send_stage_constants(socket: libc::c_int) -> Result<(), i64> {
-    let mut buf = vec![];
-    for (kind, name) in constants::STAGE_KINDS.iter() {
-        let name_bytes = name.as_bytes();
-        let [kind0, kind1] = (*kind as u16).to_be_bytes();
-        let data = &[MessageType::MappingInfoStageKinds.into(), kind0, kind1, name_bytes.len() as u8];
-        let data = &[data, name_bytes].concat();
-        buf.extend_from_slice(data);
+pub fn broadcast_fighter_info(server: &Server, training: bool, entry_count: i32, state: FighterState) {
+    // Only does something while a match is being recorded, training mode
+    // sessions are not saved. Replays keep one record per fighter, with
+    // every field at full precision.
+    ReplayManager::get().lock().unwrap().record(&ServerMessage::ExtendedFighterState {
+        fields: fields::ALL,
+        full_precision: true,
+        state: state.clone(),
+    });
+
+    // Fighters are only sent once all of them reported for this frame
+    let mut batch = FrameBatch::get().lock().unwrap();
+    if let Some(previous) = batch.add(state) {
+        broadcast_frame_state(server, training, previous);
+    }
+    if let Some(frame) = batch.take_if_complete(entry_count.max(1) as usize) {
+        broadcast_frame_state(server, training, frame);
+    }
+}
+
+pub fn broadcast_hitboxes(server: &Server, training: bool, frame: u32, entry_id: i32, hitboxes: Vec<Hitbox>) {
+    // Most frames nobody is attacking
+    if hitboxes.is_empty() {
+        return;
+    }
+    let msg = ServerMessage::HitboxState {
+        frame: frame,
+        entry_id: entry_id as u8,
+        hitboxes: hitboxes,
+    };
+    ReplayManager::get().lock().unwrap().record(&msg);
+    // Part of the frame state, so streamed along with it
+    if Config::get().stream_frame_states(training) {
+        server.broadcast(&msg);
     }
-    send_bytes(socket, &buf)?;
-    Ok(())
 }
 
-fn send_fighter_status_kind_constants(socket: libc::c_int) -> Result<(), i64> {
-    for (status, fighter, name) in constants::FIGHTER_STATUS_KINDS.iter() {
-        let name_bytes = name.as_bytes();
-        let [status0, status1] = (status.as_lua_int().get_int() as u16).to_be_bytes();
-        let data = &[MessageType::MappingInfoFighterStatusKinds.into(), fighter.as_lua_int().get_int() as u8, status0, status1, name_bytes.len() as u8];
-        let data = &[data, name_bytes].concat();
-        send_bytes(socket, &data)?;
+fn send_fighter_kind_constants(client: &Mutex<Client>) -> io::Result<()> {
+    let msgs: Vec<ServerMessage> = MappingInfo::get().fighter_kinds.iter()
+        .map(|(kind, name)| ServerMessage::MappingInfoFighterKind {
+            fighter_kind: *kind,
+            name: name.to_string(),
+        })
+        .collect();
+    send_messages(client, &msgs)
+}
+
+fn send_stage_constants(client: &Mutex<Client>) -> io::Result<()> {
+    let msgs: Vec<ServerMessage> = MappingInfo::get().stage_kinds.iter()
+        .map(|(kind, name)| ServerMessage::MappingInfoStageKind {
+            stage_kind: *kind,
+            name: name.to_string(),
+        })
+        .collect();
+    send_messages(client, &msgs)
+}
+
+fn send_fighter_status_kind_constants(client: &Mutex<Client>) -> io::Result<()> {
+    let msgs: Vec<ServerMessage> = MappingInfo::get().fighter_status_kinds.iter()
+        .map(|(fighter, status, name)| ServerMessage::MappingInfoFighterStatusKind {
+            fighter_kind: *fighter,
+            status_kind: *status,
+            name: name.to_string(),
+        })
+        .collect();
+
+    if client.lock().unwrap().is_framed() {
+        return send_messages(client, &msgs);
+    }
+
+    // Protocol 1.1 clients can't deal with more than one of these per recv()
+    for msg in msgs.iter() {
+        send_message(client, msg)?;
 
         // Something horrible happens if we don't do this
         std::thread::sleep(std::time::Duration::from_millis(3));
@@ -308,15 +392,200 @@ fn send_fighter_status_kind_constants(socket: libc::c_int) -> Result<(), i64> {
     Ok(())
 }
 
-fn send_hit_status_constants(socket: libc::c_int) -> Result<(), i64> {
-    let mut buf = vec![];
-    for (kind, name) in constants::HIT_STATUS_KINDS.iter() {
-        let name_bytes = name.as_bytes();
-        let data = &[MessageType::MappingInfoHitStatusKinds.into(), kind.as_lua_int().get_int() as u8, name_bytes.len() as u8];
-        let data = &[data, name_bytes].concat();
-        buf.extend_from_slice(data);
+fn send_hit_status_constants(client: &Mutex<Client>) -> io::Result<()> {
+    let msgs: Vec<ServerMessage> = MappingInfo::get().hit_status_kinds.iter()
+        .map(|(kind, name)| ServerMessage::MappingInfoHitStatusKind {
+            hit_status_kind: *kind,
+            name: name.to_string(),
+        })
+        .collect();
+    send_messages(client, &msgs)
+}
+
+pub fn send_pong(client: &Mutex<Client>, timestamp: u64) -> io::Result<()> {
+    client.lock().unwrap().send(&ServerMessage::Pong { timestamp: timestamp })
+}
+
+/// Port 0 switches back to TCP
+pub fn request_udp(client: &Mutex<Client>, port: u16) -> io::Result<()> {
+    let mut client = client.lock().unwrap();
+    let local_port = if port == 0 {
+        client.stop_udp();
+        0
+    } else if !Config::get().udp() || !client.is_framed() {
+        // Unframed clients couldn't tell where a datagram's message ends
+        info!("Refusing UDP request of client #{}", client.id());
+        0
+    } else {
+        match client.start_udp(port) {
+            Ok(local_port) => local_port,
+            Err(e) => {
+                warn!("Failed to open UDP socket for client #{}: {}", client.id(), e);
+                0
+            }
+        }
+    };
+    client.send(&ServerMessage::UdpResponse { port: local_port })
+}
+
+pub fn send_client_list(client: &Mutex<Client>, clients: Vec<ClientInfo>) -> io::Result<()> {
+    debug!("Sending list of {} clients", clients.len());
+    send_message(client, &ServerMessage::ClientList(clients))
+}
+
+pub fn send_replay_list(client: &Mutex<Client>) -> io::Result<()> {
+    let replays = replay::list_replays();
+    debug!("Sending list of {} replays", replays.len());
+
+    let mut msgs: Vec<ServerMessage> = replays.into_iter()
+        .map(ServerMessage::ReplayListEntry)
+        .collect();
+    msgs.push(ServerMessage::ReplayListComplete);
+    send_messages(client, &msgs)
+}
+
+pub fn send_replay(client: &Mutex<Client>, name: &str, offset: u32) -> io::Result<()> {
+    let mut offset = offset;
+
+    // A complete message with a size of 0 tells the client the download failed.
+    // Replay files are never empty because they always have a header
+    let mut file = match replay::open_replay(name) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open replay {}: {}", name, e);
+            send_message(client, &ServerMessage::ReplayDownloadComplete { size: 0 })?;
+            return Ok(());
+        }
+    };
+    let size = file.metadata().map(|m| m.len() as u32).unwrap_or(0);
+    if offset > size || file.seek(SeekFrom::Start(offset as u64)).is_err() {
+        offset = size;
+    }
+
+    debug!("Sending replay {} starting at offset {} of {}", name, offset, size);
+    send_replay_chunks(&mut file, name, offset, |msg| send_message(client, &msg))
+}
+
+// Sends the rest of the file from `offset` on, then ReplayDownloadComplete
+// with the size of the file, or 0 if reading it failed half way
+fn send_replay_chunks<R: Read>(
+    file: &mut R,
+    name: &str,
+    offset: u32,
+    mut send: impl FnMut(ServerMessage) -> io::Result<()>
+) -> io::Result<()> {
+    let mut offset = offset;
+    let mut chunk = [0u8; REPLAY_CHUNK_SIZE];
+    loop {
+        let len = match file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Failed to read replay {}: {}", name, e);
+                return send(ServerMessage::ReplayDownloadComplete { size: 0 });
+            }
+        };
+
+        send(ServerMessage::ReplayDownloadChunk {
+            offset: offset,
+            data: chunk[..len].to_vec(),
+        })?;
+        offset += len as u32;
     }
-    send_bytes(socket, &buf)?;
-    Ok(())
+
+    send(ServerMessage::ReplayDownloadComplete { size: offset })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_version_without_handshake() {
+        assert_eq!(select_protocol_version((1, 1)), PROTOCOL_VERSION_LEGACY);
+        assert_eq!(select_protocol_version((1, 2)), PROTOCOL_VERSION_FRAMED);
+        // Newer clients get the newest version this server has
+        assert_eq!(select_protocol_version((1, 9)), PROTOCOL_VERSION_FRAMED);
+        assert_eq!(select_protocol_version((2, 0)), PROTOCOL_VERSION_FRAMED);
+        // Unknown older versions are treated like the oldest one
+        assert_eq!(select_protocol_version((0, 3)), PROTOCOL_VERSION_LEGACY);
+    }
+
+    #[test]
+    fn handshake_picks_highest_common_version() {
+        assert_eq!(negotiate((1, 1), (1, 2), 0), Ok((PROTOCOL_VERSION_FRAMED, 0)));
+        assert_eq!(negotiate((1, 1), (1, 1), 0), Ok((PROTOCOL_VERSION_LEGACY, 0)));
+        assert_eq!(negotiate((1, 0), (3, 0), 0), Ok((PROTOCOL_VERSION_MAX, 0)));
+        assert!(negotiate((1, 3), (2, 0), 0).is_err());
+        assert!(negotiate((0, 1), (1, 0), 0).is_err());
+    }
+
+    #[test]
+    fn handshake_intersects_capabilities() {
+        let all = u32::MAX;
+        assert_eq!(negotiate((1, 2), (1, 2), all), Ok((PROTOCOL_VERSION_FRAMED, SERVER_CAPABILITIES)));
+        assert_eq!(negotiate((1, 2), (1, 2), capabilities::HEARTBEAT | 1 << 31),
+            Ok((PROTOCOL_VERSION_FRAMED, capabilities::HEARTBEAT)));
+
+        // Unframed clients can't parse what the framed only ones change
+        let (_, enabled) = negotiate((1, 1), (1, 1), all).unwrap();
+        assert_eq!(enabled, SERVER_CAPABILITIES & !FRAMED_ONLY_CAPABILITIES);
+        assert!(capabilities::has(enabled, capabilities::MULTI_PLAYER));
+
+        // Full precision needs the extended layout
+        let (_, enabled) = negotiate((1, 2), (1, 2), capabilities::FULL_PRECISION | capabilities::COMPRESSION).unwrap();
+        assert_eq!(enabled, capabilities::COMPRESSION);
+        let (_, enabled) = negotiate((1, 2), (1, 2), capabilities::FULL_PRECISION | capabilities::EXTENDED_FIGHTER_FIELDS).unwrap();
+        assert_eq!(enabled, capabilities::FULL_PRECISION | capabilities::EXTENDED_FIGHTER_FIELDS);
+    }
+
+    // Returns `len` bytes, then fails like an SD card read error would
+    struct FailingReader {
+        len: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.len == 0 {
+                return Err(io::Error::other("read error"));
+            }
+            let len = self.len.min(buf.len());
+            self.len -= len;
+            Ok(len)
+        }
+    }
+
+    fn replay_messages<R: Read>(file: &mut R, offset: u32) -> Vec<ServerMessage> {
+        let mut msgs = Vec::new();
+        send_replay_chunks(file, "replay_1.rfr", offset, |msg| {
+            msgs.push(msg);
+            Ok(())
+        }).unwrap();
+        msgs
+    }
+
+    fn chunk_offsets(msgs: &[ServerMessage]) -> Vec<(u32, usize)> {
+        msgs.iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::ReplayDownloadChunk { offset, data } => Some((*offset, data.len())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_is_sent_in_chunks() {
+        let mut file = io::Cursor::new(vec![0u8; REPLAY_CHUNK_SIZE + 10]);
+        let msgs = replay_messages(&mut file, 100);
+        assert_eq!(chunk_offsets(&msgs), vec![(100, REPLAY_CHUNK_SIZE), (100 + REPLAY_CHUNK_SIZE as u32, 10)]);
+        assert_eq!(msgs.last(), Some(&ServerMessage::ReplayDownloadComplete { size: 100 + REPLAY_CHUNK_SIZE as u32 + 10 }));
+    }
+
+    #[test]
+    fn failed_read_reports_size_zero() {
+        let mut file = FailingReader { len: REPLAY_CHUNK_SIZE + 10 };
+        let msgs = replay_messages(&mut file, 0);
+        assert_eq!(chunk_offsets(&msgs), vec![(0, REPLAY_CHUNK_SIZE), (REPLAY_CHUNK_SIZE as u32, 10)]);
+        assert_eq!(msgs.last(), Some(&ServerMessage::ReplayDownloadComplete { size: 0 }));
+    }
+}
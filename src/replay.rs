@@ -1,12 +1,45 @@
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use reframed_codec::replay::{self as format, ReplayHeader};
+use reframed_codec::{DecodeError, ReplayListEntry, ServerMessage};
+use log::{error, info, warn};
+
+use crate::config::Config;
 
 lazy_static!{
     static ref REPLAY_MANAGER: Mutex<ReplayManager> = Mutex::new(ReplayManager::new());
 }
 
+// See reframed_codec::replay for the layout of the files in here
+#[cfg(feature = "skyline")]
+pub const REPLAY_DIR: &str = "sd:/ultimate/ReFramed/replays";
+#[cfg(not(feature = "skyline"))]
+pub const REPLAY_DIR: &str = "ReFramed/replays";
+
+/*
+ * Matches are recorded from the game's per-frame hook, which must never wait
+ * on the SD card. The messages are only encoded there and handed to a writer
+ * thread, which owns the replay file and deletes old replays once a match
+ * is over.
+ */
+enum Command {
+    Start { mapping_info_checksum: u32, timestamp: u64 },
+    Record(Vec<u8>),
+    Stop,
+}
+
 pub struct ReplayManager {
-};
+    sender: mpsc::Sender<Command>,
+    // Between start_recording() and stop_recording()
+    started: bool,
+    // Set by the writer thread while a replay file is open
+    recording: Arc<AtomicBool>,
+}
 
 impl ReplayManager {
     pub fn get() -> &'static Mutex<Self> {
@@ -14,8 +47,250 @@ impl ReplayManager {
     }
 
     pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let recording = Arc::new(AtomicBool::new(false));
+        let writer_recording = recording.clone();
+        thread::spawn(move || {
+            write_replays(receiver, &writer_recording);
+        });
         Self {
+            sender: sender,
+            started: false,
+            recording: recording,
+        }
+    }
+
+    /// True once the writer thread opened the replay file, until it's closed
+    /// or writing to it failed
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    pub fn start_recording(&mut self, mapping_info_checksum: u32, match_start: &ServerMessage) {
+        if !Config::get().record_replays() {
+            return;
+        }
+
+        if self.is_recording() {
+            warn!("New match started while still recording, closing the previous replay");
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        self.started = true;
+        self.send(Command::Start {
+            mapping_info_checksum: mapping_info_checksum,
+            timestamp: timestamp,
+        });
+        self.record(match_start);
+    }
+
+    pub fn record(&mut self, msg: &ServerMessage) {
+        // Messages sent before the writer thread opened the file are queued
+        // until it did
+        if !self.started {
+            return;
+        }
+
+        // Records always use the unframed encoding, the record itself
+        // already has a length
+        let mut record = Vec::new();
+        format::encode_record(&mut record, &msg.to_bytes());
+        self.send(Command::Record(record));
+    }
+
+    pub fn stop_recording(&mut self, match_end: &ServerMessage) {
+        if !self.started {
+            return;
+        }
+
+        self.record(match_end);
+        self.send(Command::Stop);
+        self.started = false;
+    }
+
+    fn send(&mut self, command: Command) {
+        if self.sender.send(command).is_err() {
+            error!("Replay writer thread is gone, stopping recording");
+            self.started = false;
+        }
+    }
+}
+
+struct ReplayFile {
+    path: String,
+    writer: BufWriter<fs::File>,
+}
+
+impl ReplayFile {
+    fn create(mapping_info_checksum: u32, timestamp: u64) -> io::Result<Self> {
+        fs::create_dir_all(REPLAY_DIR)?;
+
+        // Two matches can start within the same second, never overwrite the
+        // earlier one
+        let mut suffix = 0;
+        let (path, file) = loop {
+            let path = if suffix == 0 {
+                format!("{}/replay_{}.rfr", REPLAY_DIR, timestamp)
+            } else {
+                format!("{}/replay_{}_{}.rfr", REPLAY_DIR, timestamp, suffix)
+            };
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut writer = BufWriter::new(file);
+        let mut header = Vec::new();
+        ReplayHeader::new(mapping_info_checksum, timestamp).encode(&mut header);
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            path: path,
+            writer: writer,
+        })
+    }
+
+    fn close(mut self) {
+        match self.writer.flush() {
+            Ok(_) => info!("Finished recording replay {}", self.path),
+            Err(e) => error!("Failed to flush replay file {}: {}", self.path, e),
+        }
+    }
+}
+
+fn write_replays(receiver: mpsc::Receiver<Command>, recording: &AtomicBool) {
+    let mut file: Option<ReplayFile> = None;
+    for command in receiver {
+        match command {
+            Command::Start { mapping_info_checksum, timestamp } => {
+                if let Some(previous) = file.take() {
+                    previous.close();
+                }
+                file = match ReplayFile::create(mapping_info_checksum, timestamp) {
+                    Ok(replay) => {
+                        info!("Recording replay to {}", replay.path);
+                        Some(replay)
+                    },
+                    Err(e) => {
+                        error!("Failed to create replay file in {}: {}", REPLAY_DIR, e);
+                        None
+                    }
+                };
+            },
+            Command::Record(record) => {
+                if let Some(replay) = file.as_mut() {
+                    if let Err(e) = replay.writer.write_all(&record) {
+                        error!("Failed to write to replay file {}: {}, stopping recording", replay.path, e);
+                        file = None;
+                    }
+                }
+            },
+            Command::Stop => {
+                if let Some(replay) = file.take() {
+                    replay.close();
+                }
+                delete_old_replays(Config::get().max_replays());
+            },
+        }
+        recording.store(file.is_some(), Ordering::Relaxed);
+    }
+}
+
+fn is_valid_replay_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.ends_with(".rfr")
+        && !name.contains('/')
+        && !name.contains('\\')
+        && !name.contains("..")
+}
+
+fn invalid_data(e: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn read_replay_info(name: &str) -> io::Result<ReplayListEntry> {
+    let mut file = fs::File::open(format!("{}/{}", REPLAY_DIR, name))?;
+    let size = file.metadata()?.len();
+
+    let mut header = [0u8; format::HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    let header = ReplayHeader::decode(&header).map_err(invalid_data)?;
+
+    // The first record is always MatchStart, which has the stage, fighters
+    // and tags we want to list
+    let mut len = [0u8; 2];
+    file.read_exact(&mut len)?;
+    let mut record = vec![0u8; 2 + u16::from_be_bytes(len) as usize];
+    record[..2].copy_from_slice(&len);
+    file.read_exact(&mut record[2..])?;
+    let match_start = match format::decode_record(&record).map_err(invalid_data)? {
+        (ServerMessage::MatchStart(info), _) => info,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "first record is not MatchStart")),
+    };
+
+    Ok(ReplayListEntry {
+        name: name.to_string(),
+        size: u32::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "replay is larger than 4 GiB"))?,
+        timestamp: header.timestamp,
+        mapping_info_checksum: header.mapping_info_checksum,
+        match_start: match_start,
+    })
+}
+
+/// Returns information on every readable replay in the replay directory,
+/// oldest first. Files that fail to parse are skipped.
+pub fn list_replays() -> Vec<ReplayListEntry> {
+    let entries = match fs::read_dir(REPLAY_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut replays: Vec<ReplayListEntry> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_valid_replay_name(name))
+        .filter_map(|name| match read_replay_info(&name) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                warn!("Skipping replay {}: {}", name, e);
+                None
+            }
+        })
+        .collect();
+    replays.sort_by_key(|info| info.timestamp);
+    replays
+}
+
+/// Deletes the oldest replays until at most `max_replays` are left. 0 means
+/// there is no limit.
+fn delete_old_replays(max_replays: usize) {
+    if max_replays == 0 {
+        return;
+    }
+
+    let replays = list_replays();
+    let excess = replays.len().saturating_sub(max_replays);
+    for replay in replays.iter().take(excess) {
+        let path = format!("{}/{}", REPLAY_DIR, replay.name);
+        match fs::remove_file(&path) {
+            Ok(_) => info!("Deleted old replay {}", path),
+            Err(e) => warn!("Failed to delete old replay {}: {}", path, e),
         }
     }
 }
 
+/// Opens a replay file for reading. The name must be a plain file name as
+/// returned by list_replays(), paths are rejected.
+pub fn open_replay(name: &str) -> io::Result<fs::File> {
+    if !is_valid_replay_name(name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid replay name"));
+    }
+    fs::File::open(format!("{}/{}", REPLAY_DIR, name))
+}
@@ -1,204 +1,846 @@
 use std::vec::Vec;
 use std::thread;
-use std::sync;
-use std::mem::size_of_val;
-use skyline::libc;
+use std::sync::{self, Arc};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use reframed_codec::{capabilities, fields, ClientInfo, ClientMessage, DecodeError, FrameState, ServerMessage, Subscription};
+use reframed_codec::delta::DeltaEncoder;
+use reframed_codec::udp;
+use reframed_codec::websocket::{self, opcode};
+use log::{debug, error, info, warn};
 
+use crate::config::Config;
+use crate::json;
+use crate::outbox::Outbox;
 use crate::protocol;
 
+// Lets the console notice clients that went away without closing the
+// connection, e.g. a laptop that lost Wi-Fi
+#[cfg(feature = "skyline")]
+fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    use skyline::libc;
+    use std::os::unix::io::AsRawFd;
+
+    let flags: u32 = 1;
+    unsafe {
+        if libc::setsockopt(stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_KEEPALIVE,
+                &flags as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&flags) as u32) < 0 {
+            return Err(io::Error::from_raw_os_error(*libc::errno_loc()));
+        }
+    }
+    Ok(())
+}
+
+// The simulator only runs on a PC for testing, it doesn't need keep-alive
+#[cfg(not(feature = "skyline"))]
+fn set_keepalive(_stream: &TcpStream) -> io::Result<()> {
+    Ok(())
+}
+
+/// How messages are put on the wire
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    /// reframed_codec messages, see Client::set_framed()
+    Binary,
+    /// JSON text frames over a WebSocket, see json.rs
+    WebSocket,
+    /// One JSON object per line in both directions, for debugging. Binary
+    /// clients switch to this by sending json::JSON_LINES_REQUEST.
+    JsonLines,
+}
+
+// How often the heartbeat thread checks for idle clients
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Frame states of clients that asked for them to be sent over UDP
+struct UdpTarget {
+    socket: UdpSocket,
+    sequence: u32,
+}
+
 pub struct Client {
-    socket: libc::c_int,
-    allow_broadcasts: bool
+    id: u32,
+    peer: SocketAddr,
+    // Seconds since unix epoch
+    connected_at: u64,
+    stream: TcpStream,
+    encoding: Encoding,
+    outbox: Arc<Outbox>,
+    framed: bool,
+    capabilities: u32,
+    // Set while a match is running that the client doesn't support
+    skipping_match: bool,
+    allow_broadcasts: bool,
+    subscription: Subscription,
+    // Counts frames up to the subscription's decimation
+    decimation_counter: u8,
+    // Entry ID and fighter kind of everyone in the current session, so that
+    // JSON can name status kinds
+    fighter_kinds: Vec<(u8, u8)>,
+    // For the heartbeat and idle timeout, see Server::send_heartbeats()
+    last_received: Instant,
+    last_ping: Instant,
+    udp: Option<UdpTarget>,
+    // What was last sent to clients with capabilities::COMPRESSION
+    delta: DeltaEncoder,
+}
+
+// Fighter states are encoded differently depending on the client, clients
+// with the same format can share the encoded bytes
+#[derive(Clone, Copy, PartialEq)]
+struct WireFormat {
+    encoding: Encoding,
+    framed: bool,
+    fields: Option<u8>,
+    full_precision: bool,
+    entries: u8,
+    // Delta frame states depend on what the client was sent before and
+    // can't be shared
+    delta: bool,
 }
 
 impl Client {
-    pub fn new(socket: libc::c_int) -> Self {
+    pub fn new(id: u32, stream: TcpStream, peer: SocketAddr, encoding: Encoding) -> Self {
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
         Self {
-            socket: socket,
+            id: id,
+            peer: peer,
+            connected_at: connected_at,
+            stream: stream,
+            encoding: encoding,
+            outbox: Arc::new(Outbox::new()),
+            framed: false,
+            // Clients from before the handshake have always been sent the
+            // player count and can deal with any number of players
+            capabilities: capabilities::MULTI_PLAYER,
+            skipping_match: false,
             allow_broadcasts: false,
+            subscription: Subscription::all(),
+            decimation_counter: 0,
+            fighter_kinds: Vec::new(),
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+            udp: None,
+            delta: DeltaEncoder::new(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            address: self.peer.to_string(),
+            connected_at: self.connected_at,
+            capabilities: self.capabilities,
+            framed: self.framed,
+            subscribed: self.allow_broadcasts,
         }
     }
 
-    pub fn socket(&self) -> libc::c_int {
-        self.socket
+    /// Everything sent to the client goes through here, see outbox.rs
+    pub fn outbox(&self) -> &Arc<Outbox> {
+        &self.outbox
+    }
+
+    /// Switches to the length-prefixed wire format, see reframed_codec::frame.
+    /// Only applies to the binary encoding, JSON is always one message per
+    /// line or WebSocket frame.
+    pub fn set_framed(&mut self) {
+        self.framed = self.encoding == Encoding::Binary;
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn is_framed(&self) -> bool {
+        self.framed
+    }
+
+    /// Capabilities negotiated in the handshake, see reframed_codec::capabilities
+    pub fn set_capabilities(&mut self, capabilities: u32) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn has_capability(&self, capability: u32) -> bool {
+        capabilities::has(self.capabilities, capability)
+    }
+
+    /// Returns false for messages about matches the client can't display.
+    /// Has to see every message sent to the client to keep track of that.
+    pub fn accepts(&mut self, msg: &ServerMessage) -> bool {
+        match msg {
+            ServerMessage::MatchStart(info) |
+            ServerMessage::MatchResume(info) => {
+                self.fighter_kinds = info.players.iter().map(|p| (p.entry_id, p.fighter_kind)).collect();
+                self.delta.reset();
+                self.skipping_match = info.players.len() != 2
+                    && !self.has_capability(capabilities::MULTI_PLAYER);
+                !self.skipping_match
+            },
+            ServerMessage::FrameState(frame) => {
+                !self.skipping_match
+                    && frame.fighters.iter().any(|f| self.subscription.wants_entry(f.entry_id))
+                    && self.decimate()
+            },
+            ServerMessage::HitboxState { entry_id, .. } => {
+                !self.skipping_match
+                    && self.has_capability(capabilities::HITBOXES)
+                    && self.subscription.wants_entry(*entry_id)
+            },
+            ServerMessage::TrainingStart(info) |
+            ServerMessage::TrainingResume(info) => {
+                self.fighter_kinds = vec![(0, info.p1_fighter_kind), (1, info.cpu_fighter_kind)];
+                self.delta.reset();
+                true
+            },
+            ServerMessage::MatchEnd => !std::mem::replace(&mut self.skipping_match, false),
+            _ => true,
+        }
+    }
+
+    fn decimate(&mut self) -> bool {
+        let send = self.decimation_counter == 0;
+        self.decimation_counter = (self.decimation_counter + 1) % self.subscription.decimation.max(1);
+        send
+    }
+
+    /// Match and training events are only broadcast to clients that
+    /// subscribed to them
+    pub fn set_allow_broadcasts(&mut self, allow: bool) {
+        self.allow_broadcasts = allow;
+    }
+
+    pub fn set_subscription(&mut self, subscription: Subscription) {
+        self.subscription = subscription;
+        self.decimation_counter = 0;
+        self.delta.reset();
     }
 
-    pub fn set_allow_broadcasts(&mut self) {
-        self.allow_broadcasts = true;
+    fn wire_format(&self) -> WireFormat {
+        WireFormat {
+            encoding: self.encoding,
+            framed: self.framed,
+            fields: if self.has_capability(capabilities::EXTENDED_FIGHTER_FIELDS) {
+                Some(self.subscription.fields)
+            } else {
+                None
+            },
+            full_precision: self.has_capability(capabilities::FULL_PRECISION),
+            entries: self.subscription.entries,
+            delta: self.framed && self.has_capability(capabilities::COMPRESSION),
+        }
     }
 
     pub fn allow_broadcasts(&self) -> bool {
         self.allow_broadcasts
     }
+
+    pub fn encode(&mut self, msg: &ServerMessage, buf: &mut Vec<u8>) {
+        if self.encoding != Encoding::Binary {
+            self.encode_json(msg, buf);
+            return;
+        }
+
+        if let ServerMessage::FrameState(frame) = msg {
+            self.encode_frame_state(frame, buf);
+            return;
+        }
+
+        if self.framed {
+            msg.encode_framed(buf);
+        } else {
+            msg.encode(buf);
+        }
+    }
+
+    fn encode_json(&self, msg: &ServerMessage, buf: &mut Vec<u8>) {
+        let value = match msg {
+            ServerMessage::FrameState(frame) =>
+                json::message(&ServerMessage::FrameState(self.subscribed_fighters(frame)), &self.fighter_kinds),
+            _ => json::message(msg, &self.fighter_kinds),
+        };
+        if self.encoding == Encoding::WebSocket {
+            websocket::encode_frame(buf, opcode::TEXT, value.to_string().as_bytes());
+        } else {
+            buf.extend_from_slice(value.to_string().as_bytes());
+            buf.push(b'\n');
+        }
+    }
+
+    fn subscribed_fighters(&self, frame: &FrameState) -> FrameState {
+        FrameState {
+            frame: frame.frame,
+            fighters: frame.fighters.iter()
+                .filter(|f| self.subscription.wants_entry(f.entry_id))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn encode_frame_state(&mut self, frame: &FrameState, buf: &mut Vec<u8>) {
+        let frame = self.subscribed_fighters(frame);
+        let format = self.wire_format();
+
+        if !self.framed {
+            // Protocol 1.1 clients only know about single fighters
+            for fighter in frame.fighters.into_iter() {
+                ServerMessage::FighterState(fighter).encode(buf);
+            }
+        } else if format.delta {
+            let state = self.delta.encode(&frame, format.fields.unwrap_or(fields::ALL), format.full_precision);
+            ServerMessage::DeltaFrameState(state).encode_framed(buf);
+        } else if let Some(fields) = format.fields {
+            ServerMessage::ExtendedFrameState {
+                fields: fields,
+                full_precision: format.full_precision,
+                state: frame,
+            }.encode_framed(buf);
+        } else {
+            ServerMessage::FrameState(frame).encode_framed(buf);
+        }
+    }
+
+    /// Queues a message without blocking. Long replies should use
+    /// Outbox::push_wait() without holding the client's lock instead.
+    pub fn send(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        if !self.accepts(msg) {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        self.encode(msg, &mut buf);
+        self.send_bytes(buf)
+    }
+
+    /// Replies to version negotiation are always unframed, so that the
+    /// client can parse them no matter which version it asked for
+    pub fn send_unframed(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        if self.encoding == Encoding::Binary {
+            self.send_bytes(msg.to_bytes())
+        } else {
+            self.send(msg)
+        }
+    }
+
+    pub fn send_bytes(&self, bytes: Vec<u8>) -> io::Result<()> {
+        self.outbox.push(Arc::new(bytes), false)
+    }
+
+    /// Sends frame states to the given UDP port on the client's address from
+    /// now on. Returns the local port they are sent from.
+    pub fn start_udp(&mut self, port: u16) -> io::Result<u16> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect((self.peer.ip(), port))?;
+        // Broadcasts must not block, a datagram that doesn't fit into the
+        // send buffer is dropped like any other
+        socket.set_nonblocking(true)?;
+        let local_port = socket.local_addr()?.port();
+        info!("Sending frame states to client #{} over UDP, port {} -> {}", self.id, local_port, port);
+        self.udp = Some(UdpTarget { socket: socket, sequence: 0 });
+        Ok(local_port)
+    }
+
+    pub fn stop_udp(&mut self) {
+        if self.udp.take().is_some() {
+            info!("Sending frame states to client #{} over TCP", self.id);
+        }
+    }
+
+    pub fn uses_udp(&self) -> bool {
+        self.udp.is_some()
+    }
+
+    /// Falls back to TCP if the datagram can't be sent, e.g. because the
+    /// client closed the port
+    fn send_datagram(&mut self, framed_message: &[u8]) {
+        let target = match self.udp.as_mut() {
+            Some(target) => target,
+            None => return,
+        };
+        let mut buf = Vec::with_capacity(udp::HEADER_SIZE + framed_message.len());
+        udp::encode_datagram(&mut buf, target.sequence, framed_message);
+        target.sequence = target.sequence.wrapping_add(1);
+        match target.socket.send(&buf) {
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => {
+                warn!("Sending datagram to client #{} failed with {}", self.id, e);
+                self.stop_udp();
+            }
+        }
+    }
+
+    /// Only clients that can be expected to answer pings get them. Browsers
+    /// answer WebSocket pings on their own.
+    fn wants_heartbeat(&self) -> bool {
+        self.encoding == Encoding::WebSocket || self.has_capability(capabilities::HEARTBEAT)
+    }
+
+    fn send_ping(&mut self) -> io::Result<()> {
+        self.last_ping = Instant::now();
+        let timestamp = unix_millis();
+        if self.encoding == Encoding::WebSocket {
+            let mut buf = Vec::new();
+            websocket::encode_frame(&mut buf, opcode::PING, &timestamp.to_be_bytes());
+            self.send_bytes(buf)
+        } else {
+            self.send(&ServerMessage::Ping { timestamp: timestamp })
+        }
+    }
+
+    /// Called with the timestamp of a Ping sent by send_ping()
+    pub fn pong_received(&self, timestamp: u64) {
+        debug!("Round trip time to client #{}: {} ms", self.id, unix_millis().saturating_sub(timestamp));
+    }
 }
 
 pub struct Server {
-    clients: sync::Mutex<Vec<sync::Mutex<Client>>>
+    clients: sync::Mutex<Vec<Arc<sync::Mutex<Client>>>>,
+    next_client_id: AtomicU32,
 }
 
 impl Server {
     pub fn new() -> Server {
         Server {
-            clients: sync::Mutex::new(Vec::new())
+            clients: sync::Mutex::new(Vec::new()),
+            next_client_id: AtomicU32::new(1),
         }
     }
 
-    fn start_client_read_thread(&self, socket: libc::c_int /*, client: &sync::Mutex<Client>*/) -> thread::JoinHandle<()> {
+    /// Information on every connected client, ordered by ID
+    pub fn client_list(&self) -> Vec<ClientInfo> {
+        self.clients.lock().unwrap().iter()
+            .map(|client| client.lock().unwrap().info())
+            .collect()
+    }
+
+    fn log_clients(&self) {
+        let clients = self.client_list();
+        info!("{} client(s) connected{}{}",
+            clients.len(),
+            if clients.is_empty() { "" } else { ": " },
+            clients.iter()
+                .map(|c| format!("#{} {}", c.id, c.address))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
+    fn remove_client(&self, id: u32) {
+        self.clients.lock().unwrap().retain(|client| client.lock().unwrap().id() != id);
+        self.log_clients();
+    }
+
+    fn handle_message(&self, client: &sync::Mutex<Client>, msg: ClientMessage) -> io::Result<()> {
+        match msg {
+            ClientMessage::ProtocolVersion => protocol::send_protocol_version(client),
+            ClientMessage::ClientProtocolVersion { major, minor } => protocol::negotiate_protocol_version(client, major, minor),
+            ClientMessage::Handshake { min_major, min_minor, max_major, max_minor, capabilities } =>
+                protocol::handshake(client, (min_major, min_minor), (max_major, max_minor), capabilities),
+            ClientMessage::MappingInfoChecksum => protocol::send_mapping_info_checksum(client),
+            ClientMessage::MappingInfoRequest => protocol::send_mapping_info(client),
+            ClientMessage::MatchResume |
+            ClientMessage::TrainingResume => protocol::subscribe(client, Subscription::all()),
+            ClientMessage::Subscribe(subscription) => protocol::subscribe(client, subscription),
+            ClientMessage::Unsubscribe => protocol::unsubscribe(client),
+            ClientMessage::ReplayListRequest => protocol::send_replay_list(client),
+            ClientMessage::ReplayDownloadRequest { name, offset } => protocol::send_replay(client, &name, offset),
+            ClientMessage::ClientListRequest => protocol::send_client_list(client, self.client_list()),
+            ClientMessage::Ping { timestamp } => protocol::send_pong(client, timestamp),
+            ClientMessage::UdpRequest { port } => protocol::request_udp(client, port),
+            ClientMessage::Pong { timestamp } => {
+                client.lock().unwrap().pong_received(timestamp);
+                Ok(())
+            },
+        }
+    }
+
+    // Requests can span multiple recv() calls, so bytes are accumulated
+    // until a complete message can be decoded. Returns false once the
+    // connection is closed.
+    fn receive(stream: &TcpStream, client: &sync::Mutex<Client>, buf: &mut Vec<u8>) -> bool {
+        let mut chunk: [u8; 256] = [0; 256];
+        match (&*stream).read(&mut chunk) {
+            Ok(0) | Err(_) => false,
+            Ok(received) => {
+                buf.extend_from_slice(&chunk[..received]);
+                client.lock().unwrap().last_received = Instant::now();
+                true
+            }
+        }
+    }
+
+    fn start_client_read_thread(&'static self, stream: TcpStream, client: Arc<sync::Mutex<Client>>) -> thread::JoinHandle<()> {
         thread::spawn(move || {
+            let (id, encoding) = {
+                let client = client.lock().unwrap();
+                (client.id(), client.encoding())
+            };
+
+            match encoding {
+                Encoding::Binary |
+                Encoding::JsonLines => self.read_messages(&stream, &client, id),
+                Encoding::WebSocket => Self::read_websocket(&stream, &client, id),
+            }
+
+            // The writer thread shuts down the socket once everything that
+            // is still queued was sent
+            info!("Client #{} disconnected", id);
+            client.lock().unwrap().outbox().close();
+            self.remove_client(id);
+        })
+    }
+
+    /// Reads messages until the client disconnects
+    fn read_messages(&self, stream: &TcpStream, client: &sync::Mutex<Client>, id: u32) {
+        let mut buf: Vec<u8> = Vec::new();
+        while Self::receive(stream, client, &mut buf) {
             loop {
-                let mut buf: [u8; 1] = [0; 1];
-                unsafe {
-                    if libc::recv(socket, &mut buf as *mut _ as *mut libc::c_void, 1, 0) < 1 {
-                        break;
-                    }
-                }
+                // Can change after any message if the client negotiated
+                // a newer protocol version or switched to JSON
+                let (framed, encoding) = {
+                    let client = client.lock().unwrap();
+                    (client.is_framed(), client.encoding())
+                };
 
-                let send_result = match protocol::MessageType::try_from(buf[0]) {
-                    Ok(protocol::MessageType::ProtocolVersion) => protocol::send_protocol_version(socket),
-                    Ok(protocol::MessageType::MappingInfoChecksum) => protocol::send_mapping_info_checksum(socket),
-                    Ok(protocol::MessageType::MappingInfoRequest) => protocol::send_mapping_info(socket),
-                    Ok(protocol::MessageType::MappingInfoFighterKinds) => { Ok(()) },
-                    Ok(protocol::MessageType::MappingInfoFighterStatusKinds) => { Ok(()) },
-                    Ok(protocol::MessageType::MappingInfoStageKinds) => { Ok(()) },
-                    Ok(protocol::MessageType::MappingInfoHitStatusKinds) => { Ok(()) },
-                    Ok(protocol::MessageType::MappingInfoRequestComplete) => { Ok(()) },
-                    Ok(protocol::MessageType::MatchStart) => { Ok(()) },
-                    Ok(protocol::MessageType::MatchResume) => {
-                        //client.lock().unwrap().set_allow_broadcasts();
-                        protocol::send_match_resume(socket)
-                    },
-                    Ok(protocol::MessageType::MatchEnd) => { Ok(()) },
-                    Ok(protocol::MessageType::TrainingStart) => { Ok(()) },
-                    Ok(protocol::MessageType::TrainingResume) => {
-                        //client.lock().unwrap().set_allow_broadcasts();
-                        protocol::send_training_resume(socket)
-                    },
-                    Ok(protocol::MessageType::TrainingEnd) => { Ok(()) },
-                    Ok(protocol::MessageType::TrainingReset) => { Ok(()) },
-                    Ok(protocol::MessageType::FighterState) => { Ok(()) },
-                    Err(_) => {
-                        println!("[ReFramed] Received unknown message type {} from client", buf[0]);
-                        Ok(())
+                let msg = if encoding == Encoding::JsonLines || (!framed && buf.first() == json::JSON_LINES_REQUEST.as_bytes().first()) {
+                    let line = match json::take_line(&mut buf) {
+                        Ok(line) => line,
+                        Err(DecodeError::Incomplete) => break,
+                        Err(_) => {
+                            warn!("Closing connection to client #{}: line longer than {} bytes", id, json::MAX_LINE_LENGTH);
+                            return;
+                        }
+                    };
+                    if encoding != Encoding::JsonLines {
+                        if json::is_json_lines_request(&line) {
+                            info!("Client #{} switched to JSON lines", id);
+                            client.lock().unwrap().set_encoding(Encoding::JsonLines);
+                        } else {
+                            warn!("Received unknown text command from client #{}", id);
+                        }
+                        continue;
+                    }
+                    match json::client_message(&line) {
+                        Ok(Some(msg)) => msg,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Skipping message from client #{}: {}", id, e);
+                            continue;
+                        }
+                    }
+                } else if framed {
+                    match ClientMessage::decode_framed(&buf) {
+                        Ok((Ok(msg), len)) => {
+                            buf.drain(..len);
+                            msg
+                        },
+                        Ok((Err(e), len)) => {
+                            warn!("Skipping message from client #{}: {}", id, e);
+                            buf.drain(..len);
+                            continue;
+                        },
+                        Err(DecodeError::Incomplete) => break,
+                        Err(e) => {
+                            warn!("Closing connection to client #{}: {}", id, e);
+                            return;
+                        }
+                    }
+                } else {
+                    match ClientMessage::decode(&buf) {
+                        Ok((msg, len)) => {
+                            buf.drain(..len);
+                            msg
+                        },
+                        Err(DecodeError::Incomplete) => break,
+                        Err(e) => {
+                            // Without framing there's no way of knowing how
+                            // long the message is, skip one byte and hope for
+                            // the best
+                            warn!("Received {} from client #{}", e, id);
+                            buf.drain(..1);
+                            continue;
+                        }
                     }
                 };
 
-                match send_result {
-                    Ok(_) => {},
-                    Err(errno) => {
-                        println!("[ReFramed] Failed to write to client socket: {}", errno);
-                        break;
-                    }
+                if let Err(e) = self.handle_message(client, msg) {
+                    info!("Closing connection to client #{}: {}", id, e);
+                    return;
                 }
             }
-
-            println!("[ReFramed] Closing client socket");
-            unsafe {
-                libc::close(socket);
-            }
-        })
+        }
     }
 
-    pub fn listen_for_incoming_connections(&self) {
-        let server_addr: libc::sockaddr_in = libc::sockaddr_in {
-            sin_family: libc::AF_INET as _,
-            sin_port: 42069_u16.to_be(),
-            sin_len: 4,
-            sin_addr: libc::in_addr {
-                s_addr: libc::INADDR_ANY as _,
-            },
-            sin_zero: [0; 8],
-        };
+    /// Waits for the upgrade request, then answers pings until the client
+    /// closes the WebSocket. Anything else the client sends is ignored.
+    fn read_websocket(stream: &TcpStream, client: &sync::Mutex<Client>, id: u32) {
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            if !Self::receive(stream, client, &mut buf) {
+                return;
+            }
+            match websocket::decode_upgrade_request(&buf) {
+                Ok((key, len)) => {
+                    buf.drain(..len);
+                    let mut response = Vec::new();
+                    websocket::encode_upgrade_response(&mut response, &key);
+                    if client.lock().unwrap().send_bytes(response).is_err() {
+                        return;
+                    }
+                    break;
+                },
+                Err(DecodeError::Incomplete) => continue,
+                Err(e) => {
+                    warn!("Rejecting WebSocket connection from client #{}: {}", id, e);
+                    let mut response = Vec::new();
+                    websocket::encode_bad_request(&mut response);
+                    let _ = client.lock().unwrap().send_bytes(response);
+                    return;
+                }
+            }
+        }
 
-        // Create socket
-        let socket = unsafe {
-            libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0)
-        };
-        if (socket as u32 & 0x80000000) != 0 {
-            println!("[ReFramed] Failed to create socket: {}", unsafe { *libc::errno_loc() });
+        // Overlays don't negotiate anything, they get every event right away
+        debug!("Client #{} upgraded to WebSocket", id);
+        if protocol::subscribe(client, Subscription::all()).is_err() {
             return;
         }
 
-        // Enable keep-alive
-        let flags: u32 = 1;
-        unsafe {
-            if libc::setsockopt(socket,
-                    libc::SOL_SOCKET,
-                    libc::SO_KEEPALIVE,
-                    &flags as *const _ as *const libc::c_void,
-                    size_of_val(&flags) as u32) < 0 {
-                println!("[ReFramed] Failed to set socket options: {}", *libc::errno_loc());
-                libc::close(socket);
+        loop {
+            loop {
+                let frame = match websocket::Frame::decode(&buf) {
+                    Ok((frame, len)) => {
+                        buf.drain(..len);
+                        frame
+                    },
+                    Err(DecodeError::Incomplete) => break,
+                    Err(e) => {
+                        warn!("Closing WebSocket of client #{}: {}", id, e);
+                        return;
+                    }
+                };
+
+                let mut reply = Vec::new();
+                match frame.opcode {
+                    opcode::PING => websocket::encode_frame(&mut reply, opcode::PONG, &frame.payload),
+                    opcode::PONG => {
+                        // Unsolicited pongs without a timestamp are allowed
+                        // as a heartbeat
+                        if let Ok(timestamp) = <[u8; 8]>::try_from(frame.payload.as_slice()) {
+                            client.lock().unwrap().pong_received(u64::from_be_bytes(timestamp));
+                        }
+                    },
+                    opcode::CLOSE => websocket::encode_frame(&mut reply, opcode::CLOSE, &frame.payload),
+                    _ => debug!("Ignoring WebSocket frame with opcode {} from client #{}", frame.opcode, id),
+                }
+                if !reply.is_empty() && client.lock().unwrap().send_bytes(reply).is_err() {
+                    return;
+                }
+                if frame.opcode == opcode::CLOSE {
+                    return;
+                }
+            }
+
+            if !Self::receive(stream, client, &mut buf) {
                 return;
             }
         }
+    }
 
+    fn start_client_write_thread(&self, stream: TcpStream, outbox: Arc<Outbox>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while let Some(data) = outbox.pop() {
+                if let Err(e) = (&stream).write_all(&data) {
+                    warn!("send() failed with {}, removing client", e);
+                    outbox.close();
+                    break;
+                }
+            }
+
+            debug!("Closing client socket");
+            let _ = stream.shutdown(Shutdown::Both);
+        })
+    }
+
+    pub fn listen_for_incoming_connections(&'static self) {
+        self.listen(Config::get().port(), Encoding::Binary);
+    }
+
+    /// Stream overlays connect here, see json.rs
+    pub fn listen_for_websocket_connections(&'static self) {
+        self.listen(Config::get().websocket_port(), Encoding::WebSocket);
+    }
+
+    /// Only returns if the socket fails
+    fn listen(&'static self, port: u16, encoding: Encoding) {
         // Bind socket
-        unsafe {
-            if libc::bind(socket,
-                    &server_addr as *const libc::sockaddr_in as *const libc::sockaddr,
-                    size_of_val(&server_addr) as u32) < 0 {
-                println!("[ReFramed] Failed to bind socket: {}", *libc::errno_loc());
-                libc::close(socket);
+        let listener = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind socket: {}", e);
                 return;
             }
-        }
+        };
 
         // Server loop
-        println!("[ReFramed] Started server");
+        if encoding == Encoding::WebSocket {
+            info!("Accepting WebSocket connections on port {}", port);
+        } else {
+            info!("Started server on port {}", port);
+        }
         loop {
-            // Listen for incoming connection
-            unsafe {
-                if libc::listen(socket, 1) < 0 {
-                    println!("[ReFramed] Failed to listen: {}", *libc::errno_loc());
+            // Accept incoming connection
+            let (stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept client connection: {}", e);
                     break;
                 }
+            };
+
+            let max_clients = Config::get().max_clients();
+            let mut clients = self.clients.lock().unwrap();
+            if clients.len() >= max_clients {
+                warn!("Rejecting connection from {}, already serving {} clients", peer, max_clients);
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
             }
 
-            // Accept incoming connection
-            let mut addr_len: u32 = 0;
-            let client_socket = unsafe {
-                libc::accept(socket,
-                        &server_addr as *const libc::sockaddr_in as *mut libc::sockaddr,
-                        &mut addr_len)
-            };
-            if (client_socket as u32 & 0x80000000) != 0 {
-                println!("[ReFramed] Failed to accept client connection: {}", unsafe { *libc::errno_loc() });
-                break;
+            // Fighter states are small and latency sensitive, don't let them
+            // sit in the send buffer
+            let _ = stream.set_nodelay(true);
+            if let Err(e) = set_keepalive(&stream) {
+                warn!("Failed to enable keep-alive for {}: {}", peer, e);
             }
 
-            let client = sync::Mutex::new(Client::new(client_socket));
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(client);
-            self.start_client_read_thread(client_socket);
+            let (read_stream, write_stream) = match (stream.try_clone(), stream.try_clone()) {
+                (Ok(read_stream), Ok(write_stream)) => (read_stream, write_stream),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("Failed to clone client socket: {}", e);
+                    continue;
+                }
+            };
+
+            let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+            let client = Client::new(id, stream, peer, encoding);
+            let outbox = client.outbox().clone();
+            let client = Arc::new(sync::Mutex::new(client));
+            clients.push(client.clone());
+            drop(clients);
+
+            info!("Client #{} connected from {}", id, peer);
+            self.log_clients();
+            self.start_client_read_thread(read_stream, client);
+            self.start_client_write_thread(write_stream, outbox);
         }
-        println!("[ReFramed] Stopping server...");
+        info!("Stopping server...");
         for client in self.clients.lock().unwrap().iter() {
-            unsafe {
-                libc::shutdown(client.lock().unwrap().socket(), libc::SHUT_RDWR);
+            let client = client.lock().unwrap();
+            // Clients of the binary server may have switched to JSON lines
+            if (client.encoding() == Encoding::WebSocket) != (encoding == Encoding::WebSocket) {
+                continue;
             }
+            client.outbox().close();
+            let _ = client.stream().shutdown(Shutdown::Both);
         }
+    }
 
-        unsafe {
-            libc::close(socket);
+    /// Pings clients that haven't sent anything for a while and disconnects
+    /// the ones that stopped answering. Clients only talk when asked to
+    /// during a match, so this doesn't care about how much is sent to them.
+    /// Never returns.
+    pub fn send_heartbeats(&self) {
+        let interval = Duration::from_secs(Config::get().heartbeat_secs());
+        let timeout = Duration::from_secs(Config::get().idle_timeout_secs());
+        loop {
+            thread::sleep(HEARTBEAT_POLL_INTERVAL);
+            for client in self.clients.lock().unwrap().iter() {
+                let mut client = client.lock().unwrap();
+                if !client.wants_heartbeat() {
+                    continue;
+                }
+                if !timeout.is_zero() && client.last_received.elapsed() >= timeout {
+                    // The read thread notices and removes the client
+                    warn!("Client #{} timed out", client.id());
+                    let _ = client.stream().shutdown(Shutdown::Both);
+                } else if client.last_received.elapsed() >= interval && client.last_ping.elapsed() >= interval {
+                    let _ = client.send_ping();
+                }
+            }
         }
     }
 
-    pub fn broadcast(&self, data: &[u8]) {
+    /// Only queues the message, this is called from the game's hooks and
+    /// must not block on the network
+    pub fn broadcast(&self, msg: &ServerMessage) {
+        // Encode at most once per wire format
+        let mut encoded: Vec<(WireFormat, Arc<Vec<u8>>)> = Vec::new();
+
+        // Frame states and hitboxes are sent every frame, so losing one is
+        // better than falling further behind. Everything else is an event
+        // the client can't do without.
+        let droppable = matches!(msg, ServerMessage::FrameState(_) | ServerMessage::HitboxState { .. });
+
         self.clients.lock().unwrap().retain(|client| {
-            let socket = client.lock().unwrap().socket();
-            let result = unsafe {
-                libc::send(socket, data.as_ptr() as *const _, data.len(), 0)
+            let mut client = client.lock().unwrap();
+            if client.outbox().is_closed() {
+                return false;
+            }
+            if !client.allow_broadcasts() || !client.accepts(msg) {
+                return true;
+            }
+            let format = client.wire_format();
+            let cached = match encoded.iter().position(|(f, _)| *f == format) {
+                Some(index) if !(droppable && format.delta) => Some(index),
+                _ => None,
             };
-            if result < 0 {
-                println!("[ReFramed] send() failed with {}, removing client", unsafe { *libc::errno_loc() });
-                unsafe {
-                    libc::shutdown(socket, libc::SHUT_RDWR);
+            let index = match cached {
+                Some(index) => index,
+                None => {
+                    let mut buf = Vec::new();
+                    client.encode(msg, &mut buf);
+                    encoded.push((format, Arc::new(buf)));
+                    encoded.len() - 1
                 }
-                false
-            } else {
-                true
+            };
+            let data = &encoded[index].1;
+            if data.is_empty() {
+                // Nothing the client's encoding can represent
+                return true;
+            }
+            if droppable && client.uses_udp() {
+                client.send_datagram(data);
+                return true;
             }
+            client.outbox().push(data.clone(), droppable).is_ok()
         });
     }
 }
-
@@ -0,0 +1,428 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Energy, GameBackend};
+use crate::game_events;
+use crate::hitboxes::HitboxTracker;
+use crate::hitlag::HitlagTracker;
+use crate::mapping_info::MappingInfo;
+use crate::SERVER;
+use reframed_codec::Hitbox;
+
+/*
+ * Scripted stand-in for the game so that the server can be run and tested on
+ * a PC. The simulator plays through a list of scenes at 60 fps and calls the
+ * same global_reset()/per-fighter hooks the game would, in the same order as
+ * described in lib.rs.
+ */
+
+const FPS: u64 = 60;
+
+pub struct SimFighter {
+    pub fighter_kind: i32,
+    pub fighter_skin: i32,
+    pub tag: String,
+}
+
+pub enum Scene {
+    /// Menus, character select, results screen. No fighters exist.
+    Menu { frames: u32 },
+    /// A match with one entry per fighter, timed to last the given number of
+    /// frames
+    Match { stage_id: i32, fighters: Vec<SimFighter>, frames: u32 },
+    /// Training mode with a player and a CPU
+    Training { stage_id: i32, p1_fighter_kind: i32, cpu_fighter_kind: i32, frames: u32 },
+}
+
+pub struct SimScript {
+    pub scenes: Vec<Scene>,
+    /// Start over from the first scene once the last one ends
+    pub repeat: bool,
+}
+
+impl SimScript {
+    /// A 1v1, a 4 player free for all and a training session, separated by
+    /// short menu breaks
+    pub fn demo() -> Self {
+        let fighter = |kind, skin, tag: &str| SimFighter {
+            fighter_kind: kind,
+            fighter_skin: skin,
+            tag: tag.to_string(),
+        };
+
+        Self {
+            scenes: vec![
+                Scene::Menu { frames: 3 * 60 },
+                Scene::Match {
+                    stage_id: 0,
+                    fighters: vec![fighter(0, 0, "TheComet"), fighter(1, 3, "")],
+                    frames: 60 * 60,
+                },
+                Scene::Menu { frames: 5 * 60 },
+                Scene::Match {
+                    stage_id: 3,
+                    fighters: vec![
+                        fighter(2, 0, "Alpha"),
+                        fighter(3, 1, "Bravo"),
+                        fighter(0, 2, "Charlie"),
+                        fighter(1, 4, ""),
+                    ],
+                    frames: 45 * 60,
+                },
+                Scene::Menu { frames: 5 * 60 },
+                Scene::Training {
+                    stage_id: 1,
+                    p1_fighter_kind: 2,
+                    cpu_fighter_kind: 0,
+                    frames: 30 * 60,
+                },
+            ],
+            repeat: true,
+        }
+    }
+}
+
+// Frames at the start of a scene before "GO!" and at the end before the
+// results screen
+const READY_FRAMES: u32 = 90;
+const END_FRAMES: u32 = 60;
+
+// CONTROL_PAD_BUTTON_ATTACK and CONTROL_PAD_BUTTON_GUARD as bits
+const BUTTON_ATTACK: u32 = 1 << 0;
+const BUTTON_GUARD: u32 = 1 << 3;
+
+/// Game state of the current frame, read through GameBackend
+pub struct SimBackend {
+    frame: u32,
+    stage_id: i32,
+    training: bool,
+    result_mode: bool,
+    ready_go: bool,
+    duration: u32,
+    fighters: Vec<SimFighter>,
+}
+
+impl SimBackend {
+    pub fn new() -> Self {
+        Self {
+            frame: 0,
+            stage_id: -1,
+            training: false,
+            result_mode: false,
+            ready_go: false,
+            duration: 0,
+            fighters: Vec::new(),
+        }
+    }
+
+    fn enter_scene(&mut self, scene: &Scene) {
+        self.frame = 0;
+        match scene {
+            Scene::Menu { frames } => {
+                self.duration = *frames;
+                self.fighters.clear();
+            },
+            Scene::Match { stage_id, fighters, frames } => {
+                self.stage_id = *stage_id;
+                self.training = false;
+                self.result_mode = false;
+                self.duration = *frames;
+                self.fighters = fighters.iter().map(|f| SimFighter {
+                    fighter_kind: f.fighter_kind,
+                    fighter_skin: f.fighter_skin,
+                    tag: f.tag.clone(),
+                }).collect();
+            },
+            Scene::Training { stage_id, p1_fighter_kind, cpu_fighter_kind, frames } => {
+                self.stage_id = *stage_id;
+                self.training = true;
+                self.result_mode = false;
+                self.duration = *frames;
+                self.fighters = vec![
+                    SimFighter { fighter_kind: *p1_fighter_kind, fighter_skin: 0, tag: "".to_string() },
+                    SimFighter { fighter_kind: *cpu_fighter_kind, fighter_skin: 0, tag: "".to_string() },
+                ];
+            },
+        }
+    }
+
+    fn scene_length(&self) -> u32 {
+        if self.fighters.is_empty() {
+            self.duration
+        } else {
+            READY_FRAMES + self.duration + END_FRAMES
+        }
+    }
+
+    fn playing(&self) -> bool {
+        self.frame >= READY_FRAMES && self.frame < READY_FRAMES + self.duration
+    }
+
+    /// Time since "GO!" in frames
+    fn t(&self) -> f32 {
+        self.frame.saturating_sub(READY_FRAMES) as f32
+    }
+
+    fn progress(&self) -> f32 {
+        (self.t() / self.duration.max(1) as f32).min(1.0)
+    }
+}
+
+impl Default for SimBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GameBackend for SimBackend {
+    type Fighter = usize;
+
+    fn is_ready_go(&self) -> bool { self.ready_go }
+    fn is_result_mode(&self) -> bool { self.result_mode }
+    fn is_training_mode(&self) -> bool { self.training }
+    fn entry_count(&self) -> i32 { self.fighters.len() as i32 }
+    fn stage_id(&self) -> i32 { self.stage_id }
+
+    fn frames_left(&self) -> u32 {
+        if self.training {
+            // The timer doesn't run in training mode
+            return 0;
+        }
+        self.duration - (self.t() as u32).min(self.duration)
+    }
+
+    fn player_tag(&self, entry_id: i32) -> String {
+        self.fighters.get(entry_id as usize).map(|f| f.tag.clone()).unwrap_or_default()
+    }
+
+    fn entry_id(&self, fighter: &usize) -> i32 { *fighter as i32 }
+    fn fighter_kind(&self, fighter: &usize) -> i32 { self.fighters[*fighter].fighter_kind }
+    fn is_follower(&self, _fighter: &usize) -> bool { false }
+    fn fighter_skin(&self, fighter: &usize) -> i32 { self.fighters[*fighter].fighter_skin }
+
+    fn stock_count(&self, fighter: &usize) -> u8 {
+        // Everyone loses a stock at a slightly different time
+        let lost = (self.progress() * 3.0 + *fighter as f32 * 0.1) as u8;
+        3u8.saturating_sub(lost).max(1)
+    }
+
+    fn status_kind(&self, fighter: &usize) -> i32 {
+        // Cycle through the statuses in the simulated mapping info
+        (self.t() as i32 / 20 + *fighter as i32) % 4
+    }
+
+    fn motion_kind(&self, fighter: &usize) -> u64 {
+        0x0a_0000_0000 + self.status_kind(fighter) as u64
+    }
+
+    fn damage(&self, fighter: &usize) -> f32 {
+        (self.t() / 10.0 + *fighter as f32 * 7.0) % 150.0
+    }
+
+    fn shield_size(&self, fighter: &usize) -> f32 {
+        if self.status_kind(fighter) == 3 {
+            50.0 - (self.t() % 20.0)
+        } else {
+            50.0
+        }
+    }
+
+    fn attack_connected(&self, fighter: &usize) -> bool {
+        self.status_kind(fighter) == 2 && self.t() as u32 % 20 == 10
+    }
+
+    fn hitstun_left(&self, fighter: &usize) -> f32 {
+        if self.status_kind(fighter) == 1 {
+            20.0 - self.t() % 20.0
+        } else {
+            0.0
+        }
+    }
+
+    fn pos_x(&self, fighter: &usize) -> f32 {
+        60.0 * (self.t() / 90.0 + *fighter as f32 * 1.5).sin()
+    }
+
+    fn pos_y(&self, fighter: &usize) -> f32 {
+        30.0 * (self.t() / 40.0 + *fighter as f32).sin().abs()
+    }
+
+    fn facing(&self, fighter: &usize) -> f32 {
+        if (self.t() / 90.0 + *fighter as f32 * 1.5).cos() > 0.0 { 1.0 } else { -1.0 }
+    }
+
+    fn hit_status(&self, _fighter: &usize) -> u64 { 0 }
+
+    fn hitlag_left(&self, fighter: &usize) -> u64 {
+        HitlagTracker::get().lock().unwrap().next_frame(*fighter as u32) as u64
+    }
+
+    fn opponent_in_hitlag(&self, fighter: &usize) -> bool {
+        self.attack_connected(fighter)
+    }
+
+    fn buttons(&self, fighter: &usize) -> u32 {
+        match self.status_kind(fighter) {
+            2 => BUTTON_ATTACK,
+            3 => BUTTON_GUARD,
+            _ => 0,
+        }
+    }
+
+    fn buttons_pressed(&self, fighter: &usize) -> u32 {
+        // Statuses change every 20 frames
+        if self.t() % 20.0 < 1.0 { self.buttons(fighter) } else { 0 }
+    }
+
+    fn stick_x(&self, fighter: &usize) -> f32 {
+        // Pointing where the fighter moves, see pos_x()
+        (self.t() / 90.0 + *fighter as f32 * 1.5).cos()
+    }
+
+    fn stick_y(&self, _fighter: &usize) -> f32 { 0.0 }
+    fn c_stick_x(&self, _fighter: &usize) -> f32 { 0.0 }
+    fn c_stick_y(&self, _fighter: &usize) -> f32 { 0.0 }
+
+    // Every status plays a 20 frame animation
+    fn motion_frame(&self, _fighter: &usize) -> f32 { self.t() % 20.0 }
+    fn motion_end_frame(&self, _fighter: &usize) -> f32 { 20.0 }
+    fn motion_rate(&self, _fighter: &usize) -> f32 { 1.0 }
+
+    fn speed(&self, fighter: &usize, energy: Energy) -> (f32, f32) {
+        // Derivatives of pos_x() and pos_y(). The player controls the
+        // horizontal part, the vertical part comes from the animation.
+        let x = 60.0 / 90.0 * (self.t() / 90.0 + *fighter as f32 * 1.5).cos();
+        let phase = self.t() / 40.0 + *fighter as f32;
+        let y = 30.0 / 40.0 * phase.cos() * phase.sin().signum();
+        match energy {
+            Energy::Sum => (x, y),
+            Energy::Control => (x, 0.0),
+            Energy::Motion => (0.0, y),
+            Energy::Gravity | Energy::Damage => (0.0, 0.0),
+        }
+    }
+
+    fn hitboxes(&self, fighter: &usize) -> Vec<Hitbox> {
+        // Attacks are out for the whole status, following the fighter
+        if self.status_kind(fighter) != 2 {
+            return Vec::new();
+        }
+        let mut hitboxes = HitboxTracker::get().lock().unwrap().hitboxes(*fighter as u32);
+        for hitbox in hitboxes.iter_mut() {
+            hitbox.bone_x = self.pos_x(fighter);
+            hitbox.bone_y = self.pos_y(fighter);
+        }
+        hitboxes
+    }
+}
+
+/// Mapping info matching the values the simulator produces
+pub fn mapping_info() -> MappingInfo {
+    MappingInfo::new(
+        vec![
+            (0, "FIGHTER_KIND_MARIO"),
+            (1, "FIGHTER_KIND_DONKEY"),
+            (2, "FIGHTER_KIND_LINK"),
+            (3, "FIGHTER_KIND_SAMUS"),
+        ],
+        vec![
+            (0, "Battlefield"),
+            (1, "Battlefield (Omega)"),
+            (3, "Final Destination"),
+        ],
+        vec![
+            (255, 0, "FIGHTER_STATUS_KIND_WAIT"),
+            (255, 1, "FIGHTER_STATUS_KIND_DAMAGE"),
+            (255, 2, "FIGHTER_STATUS_KIND_ATTACK"),
+            (255, 3, "FIGHTER_STATUS_KIND_GUARD"),
+        ],
+        vec![
+            (0, "HIT_STATUS_OFF"),
+            (1, "HIT_STATUS_NORMAL"),
+        ]
+    )
+}
+
+/// Plays the script, calling into the server like the game hooks would. Only
+/// returns if the script doesn't repeat.
+pub fn run(script: &SimScript) {
+    let mut game = SimBackend::new();
+    let frame_time = Duration::from_nanos(1_000_000_000 / FPS);
+    let mut next_frame = Instant::now();
+
+    loop {
+        for scene in script.scenes.iter() {
+            game.enter_scene(scene);
+            while game.frame < game.scene_length() {
+                step(&mut game);
+                next_frame += frame_time;
+                if let Some(delay) = next_frame.checked_duration_since(Instant::now()) {
+                    thread::sleep(delay);
+                }
+            }
+        }
+
+        if !script.repeat {
+            break;
+        }
+    }
+}
+
+fn step(game: &mut SimBackend) {
+    let is_menu = game.fighters.is_empty();
+    if is_menu {
+        game.frame += 1;
+        return;
+    }
+
+    // The game resets all fighters right before "GO!", and again when
+    // switching to the results screen
+    if game.frame == 0 {
+        game.ready_go = false;
+        for _ in 0..game.fighters.len() {
+            game_events::global_reset(game, &SERVER);
+        }
+    }
+    game.ready_go = game.playing();
+
+    // Stands in for the game's ATTACK and StopModule calls hooked in lib.rs.
+    // This only exercises sending the values to clients, whether the hooks
+    // catch every hit can only be checked on a console.
+    for fighter in 0..game.fighters.len() {
+        if game.status_kind(&fighter) == 2 && game.t() % 20.0 < 1.0 {
+            HitboxTracker::get().lock().unwrap().define(fighter as u32, Hitbox {
+                id: 0,
+                kind: 0x0b_0000_0000,
+                bone: 0x0c_0000_0000,
+                offset_y: 5.0,
+                size: 4.0,
+                damage: 12.0,
+                angle: 361,
+                knockback_growth: 100,
+                base_knockback: 30,
+                ..Hitbox::default()
+            });
+        }
+        if game.attack_connected(&fighter) {
+            HitlagTracker::get().lock().unwrap().set_hitlag(fighter as u32, 8);
+        }
+    }
+
+    for fighter in 0..game.fighters.len() {
+        game_events::fighter_frame(game, &fighter, &SERVER);
+    }
+
+    if game.frame == READY_FRAMES + game.duration && !game.training {
+        game.result_mode = true;
+        for _ in 0..game.fighters.len() {
+            game_events::global_reset(game, &SERVER);
+        }
+    }
+
+    game.frame += 1;
+}
+
+/// Starts the server on background threads, same as the plugin's main()
+pub fn start_server() {
+    crate::start_server_threads();
+}
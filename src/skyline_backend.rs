@@ -0,0 +1,242 @@
+use smash::app::{self, lua_bind, smashball, utility};
+use smash::lib::lua_const;
+use smash::phx::{Hash40, Vector3f};
+use reframed_codec::Hitbox;
+
+use crate::backend::{Energy, GameBackend};
+use crate::hitboxes::HitboxTracker;
+use crate::hitlag::HitlagTracker;
+use crate::player_tags;
+
+extern "C" {
+    #[link_name="\u{1}_ZN3app14sv_information27get_remaining_time_as_frameEv"]
+    pub fn get_remaining_time_as_frame() -> u32;
+
+    #[link_name="\u{1}_ZN3app14sv_information8stage_idEv"]
+    pub fn get_stage_id() -> i32;
+
+    #[link_name="\u{1}_ZN2nn8settings6system17GetDeviceNickNameEPNS1_14DeviceNickNameE"]
+    fn get_device_nickname(nickname: *mut DeviceNickName);
+}
+
+#[repr(C)]
+struct DeviceNickName {
+    name: [u8; 0x80],
+}
+
+/// The name of the console as set in the system settings
+pub fn device_nickname() -> String {
+    let mut nickname = DeviceNickName { name: [0; 0x80] };
+    unsafe { get_device_nickname(&mut nickname) };
+    let len = nickname.name.iter().position(|&b| b == 0).unwrap_or(nickname.name.len());
+    String::from_utf8_lossy(&nickname.name[..len]).into_owned()
+}
+
+/// Unique for every fighter, item and projectile. The module accessor has
+/// no getter for it, it's stored right after the vtable.
+pub fn battle_object_id(module_accessor: *mut app::BattleObjectModuleAccessor) -> u32 {
+    unsafe { *(module_accessor as *const u32).add(2) }
+}
+
+pub struct SkylineBackend {
+    fighter_manager: *mut app::FighterManager,
+}
+
+impl SkylineBackend {
+    pub fn new(fighter_manager: *mut app::FighterManager) -> Self {
+        Self {
+            fighter_manager: fighter_manager,
+        }
+    }
+}
+
+impl GameBackend for SkylineBackend {
+    type Fighter = *mut app::BattleObjectModuleAccessor;
+
+    fn is_ready_go(&self) -> bool {
+        unsafe { lua_bind::FighterManager::is_ready_go(self.fighter_manager) }
+    }
+
+    fn is_result_mode(&self) -> bool {
+        unsafe { lua_bind::FighterManager::is_result_mode(self.fighter_manager) }
+    }
+
+    fn is_training_mode(&self) -> bool {
+        unsafe { smashball::is_training_mode() }
+    }
+
+    fn entry_count(&self) -> i32 {
+        unsafe { lua_bind::FighterManager::entry_count(self.fighter_manager) }
+    }
+
+    fn stage_id(&self) -> i32 {
+        unsafe { get_stage_id() }
+    }
+
+    fn frames_left(&self) -> u32 {
+        unsafe { get_remaining_time_as_frame() }
+    }
+
+    fn player_tag(&self, entry_id: i32) -> String {
+        player_tags::get_name_for_slot(entry_id)
+    }
+
+    fn entry_id(&self, fighter: &Self::Fighter) -> i32 {
+        unsafe { lua_bind::WorkModule::get_int(*fighter, *lua_const::FIGHTER_INSTANCE_WORK_ID_INT_ENTRY_ID) as i32 }
+    }
+
+    fn fighter_kind(&self, fighter: &Self::Fighter) -> i32 {
+        unsafe { utility::get_kind(&mut **fighter) }
+    }
+
+    fn is_follower(&self, fighter: &Self::Fighter) -> bool {
+        self.fighter_kind(fighter) == *lua_const::FIGHTER_KIND_NANA
+    }
+
+    fn fighter_skin(&self, fighter: &Self::Fighter) -> i32 {
+        unsafe { lua_bind::WorkModule::get_int(*fighter, *lua_const::FIGHTER_INSTANCE_WORK_ID_INT_COLOR) as i32 }
+    }
+
+    fn stock_count(&self, fighter: &Self::Fighter) -> u8 {
+        let entry_id = self.entry_id(fighter);
+        unsafe {
+            let fighter_information = lua_bind::FighterManager::get_fighter_information(
+                self.fighter_manager, app::FighterEntryID(entry_id)) as *mut app::FighterInformation;
+            lua_bind::FighterInformation::stock_count(fighter_information) as u8
+        }
+    }
+
+    fn status_kind(&self, fighter: &Self::Fighter) -> i32 {
+        unsafe { lua_bind::StatusModule::status_kind(*fighter) }
+    }
+
+    fn motion_kind(&self, fighter: &Self::Fighter) -> u64 {
+        unsafe { lua_bind::MotionModule::motion_kind(*fighter) }
+    }
+
+    fn damage(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::DamageModule::damage(*fighter, 0) }
+    }
+
+    fn shield_size(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::WorkModule::get_float(*fighter, *lua_const::FIGHTER_INSTANCE_WORK_ID_FLOAT_GUARD_SHIELD) }
+    }
+
+    fn attack_connected(&self, fighter: &Self::Fighter) -> bool {
+        unsafe { lua_bind::AttackModule::is_infliction_status(*fighter, *lua_const::COLLISION_KIND_MASK_HIT) }
+    }
+
+    fn hitstun_left(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::WorkModule::get_float(*fighter, *lua_const::FIGHTER_INSTANCE_WORK_ID_FLOAT_DAMAGE_REACTION_FRAME) }
+    }
+
+    fn pos_x(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::PostureModule::pos_x(*fighter) }
+    }
+
+    fn pos_y(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::PostureModule::pos_y(*fighter) }
+    }
+
+    fn facing(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::PostureModule::lr(*fighter) }
+    }
+
+    fn hit_status(&self, fighter: &Self::Fighter) -> u64 {
+        unsafe { lua_bind::HitModule::get_total_status(*fighter, 0) as u64 }
+    }
+
+    fn hitlag_left(&self, fighter: &Self::Fighter) -> u64 {
+        // StopModule::get_hit_stop_real_frame() doesn't work for electric
+        // attacks (such as pika dair, nair, tjolt), see hitlag.rs. It's
+        // still checked in case the game puts a fighter into hitlag without
+        // going through the hooked setters.
+        let tracked = HitlagTracker::get().lock().unwrap().next_frame(battle_object_id(*fighter)) as u64;
+        let reported = unsafe { lua_bind::StopModule::get_hit_stop_real_frame(*fighter) };
+        tracked.max(reported)
+    }
+
+    fn opponent_in_hitlag(&self, fighter: &Self::Fighter) -> bool {
+        // This is true if the opponent is in hitlag
+        unsafe { lua_bind::FighterStopModuleImpl::is_damage_stop(*fighter) }
+    }
+
+    fn buttons(&self, fighter: &Self::Fighter) -> u32 {
+        unsafe { lua_bind::ControlModule::get_button(*fighter) as u32 }
+    }
+
+    fn buttons_pressed(&self, fighter: &Self::Fighter) -> u32 {
+        // The game calls buttons that went down on this frame "triggers".
+        // This has nothing to do with the shoulder triggers, those only
+        // show up as the buttons they are mapped to in buttons().
+        unsafe { lua_bind::ControlModule::get_trigger(*fighter) as u32 }
+    }
+
+    fn stick_x(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::ControlModule::get_stick_x(*fighter) }
+    }
+
+    fn stick_y(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::ControlModule::get_stick_y(*fighter) }
+    }
+
+    fn c_stick_x(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::ControlModule::get_sub_stick_x(*fighter) }
+    }
+
+    fn c_stick_y(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::ControlModule::get_sub_stick_y(*fighter) }
+    }
+
+    fn motion_frame(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::MotionModule::frame(*fighter) }
+    }
+
+    fn motion_end_frame(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::MotionModule::end_frame(*fighter) }
+    }
+
+    fn motion_rate(&self, fighter: &Self::Fighter) -> f32 {
+        unsafe { lua_bind::MotionModule::rate(*fighter) }
+    }
+
+    fn speed(&self, fighter: &Self::Fighter, energy: Energy) -> (f32, f32) {
+        let energy_id = match energy {
+            Energy::Sum => None,
+            Energy::Control => Some(*lua_const::FIGHTER_KINETIC_ENERGY_ID_CONTROL),
+            Energy::Gravity => Some(*lua_const::FIGHTER_KINETIC_ENERGY_ID_GRAVITY),
+            Energy::Damage => Some(*lua_const::FIGHTER_KINETIC_ENERGY_ID_DAMAGE),
+            Energy::Motion => Some(*lua_const::FIGHTER_KINETIC_ENERGY_ID_MOTION),
+        };
+        unsafe {
+            match energy_id {
+                Some(energy_id) => {
+                    let energy = lua_bind::KineticModule::get_energy(*fighter, energy_id) as *mut app::KineticEnergy;
+                    if energy.is_null() {
+                        return (0.0, 0.0);
+                    }
+                    (lua_bind::KineticEnergy::get_speed_x(energy), lua_bind::KineticEnergy::get_speed_y(energy))
+                },
+                None => {
+                    let attribute = *lua_const::KINETIC_ENERGY_RESERVE_ATTRIBUTE_MAIN;
+                    (lua_bind::KineticModule::get_sum_speed_x(*fighter, attribute),
+                     lua_bind::KineticModule::get_sum_speed_y(*fighter, attribute))
+                },
+            }
+        }
+    }
+
+    fn hitboxes(&self, fighter: &Self::Fighter) -> Vec<Hitbox> {
+        let mut hitboxes = HitboxTracker::get().lock().unwrap().hitboxes(battle_object_id(*fighter));
+        hitboxes.retain(|hitbox| unsafe { lua_bind::AttackModule::is_attack(*fighter, hitbox.id as i32, false) });
+        for hitbox in hitboxes.iter_mut() {
+            let mut pos = Vector3f { x: 0.0, y: 0.0, z: 0.0 };
+            unsafe {
+                lua_bind::ModelModule::joint_global_position(*fighter, Hash40 { hash: hitbox.bone }, &mut pos, true);
+            }
+            hitbox.bone_x = pos.x;
+            hitbox.bone_y = pos.y;
+        }
+        hitboxes
+    }
+}